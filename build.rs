@@ -1,5 +1,8 @@
+use sha2::{Digest, Sha256};
 use std::env;
 use std::error::Error;
+use std::fs;
+use std::path::Path;
 use std::process::Command;
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -7,6 +10,22 @@ fn main() -> Result<(), Box<dyn Error>> {
     let rustc = env::var("RUSTC")?;
     println!("cargo:rustc-env=RUSTC={}", rustc);
 
+    // Record the compiler version, as it is part of the cache key for
+    // compiled macro crates.
+    let version = String::from_utf8(Command::new(&rustc).arg("-vV").output()?.stdout)?;
+    println!(
+        "cargo:rustc-env=CTRS_RUSTC_VERSION={}",
+        version.replace('\n', " ")
+    );
+
+    // Macros emit diagnostics with `proc_macro::Diagnostic` where it is
+    // available.
+    println!("cargo:rustc-check-cfg=cfg(ctrs_nightly)");
+    let release = version.lines().find(|line| line.starts_with("release: "));
+    if release.is_some_and(|line| line.contains("nightly") || line.contains("dev")) {
+        println!("cargo:rustc-cfg=ctrs_nightly");
+    }
+
     // Re-run this script only if the `wasm` module is changed
     let manifest_dir = env::var("CARGO_MANIFEST_DIR")?;
     println!(
@@ -50,5 +69,65 @@ cargo:rerun-if-changed={0}/wasm/.cargo/config",
         panic!("cargo exited with status {}", status);
     }
 
+    // Hash the runtime rlibs, which are also part of the cache key.
+    let mut hasher = Sha256::new();
+    for rlib in &["unicode_xid", "proc_macro2", "syn", "quote"] {
+        hasher.input(fs::read(format!(
+            "{}/wasm32-unknown-unknown/release/lib{}.rlib",
+            out_dir, rlib
+        ))?);
+    }
+    let hash = hasher.result();
+    println!(
+        "cargo:rustc-env=CTRS_RUNTIME_HASH={}",
+        hash.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()
+    );
+
+    // The native backend links macro crates against host builds of the
+    // runtime crates, which it finds in this directory.
+    if env::var_os("CARGO_FEATURE_NATIVE").is_some() {
+        println!(
+            "\
+cargo:rerun-if-changed={0}/native/Cargo.toml",
+            manifest_dir,
+        );
+        let native_dir = format!("{}/native", out_dir);
+        let status = Command::new(env::var("CARGO")?)
+            .args(&[
+                "build",
+                "--target-dir",
+                &native_dir,
+                "--release",
+                "--manifest-path",
+                &format!("{}/native/Cargo.toml", manifest_dir),
+                "-p",
+                "proc-macro2",
+                "-p",
+                "syn",
+                "-p",
+                "quote",
+                "-p",
+                "unicode-xid",
+            ])
+            // `Span::join` is used to tell which spans point into the input.
+            // Rustflags from our own build would take precedence over
+            // `RUSTFLAGS`, so are replaced.
+            .env("CARGO_ENCODED_RUSTFLAGS", "--cfg\x1fprocmacro2_semver_exempt")
+            .status()?;
+        if !status.success() {
+            panic!("cargo exited with status {}", status);
+        }
+        println!("cargo:rustc-env=CTRS_NATIVE_DIR={}/release", native_dir);
+    }
+
+    // Compiled macro crates are cached in the target directory by default.
+    // `OUT_DIR` is `$TARGET_DIR/$PROFILE/build/ctrs-$HASH/out`.
+    let out_path = Path::new(&out_dir);
+    let target_dir = out_path.ancestors().nth(4).unwrap_or(out_path);
+    println!(
+        "cargo:rustc-env=CTRS_DEFAULT_CACHE_DIR={}",
+        target_dir.join("ctrs-cache").display()
+    );
+
     Ok(())
 }
@@ -0,0 +1,2 @@
+//! Internal crate for `ctrs`, used to build the runtime crates which macro
+//! crates are linked against by the native backend.
@@ -0,0 +1,150 @@
+// Added to the source of every macro crate built natively by the native
+// backend of `ctrs`. Input and output cross into the library as text, which
+// `__ctrs_native_run` parses and prints.
+
+#[doc(hidden)]
+mod __ctrs_native {
+    use ::proc_macro2::{Delimiter, Spacing, Span, TokenStream, TokenTree};
+    use ::std::cell::RefCell;
+    use ::std::os::raw::c_void;
+    use ::std::panic::{self, AssertUnwindSafe};
+
+    thread_local! {
+        static PANIC: RefCell<Option<String>> = RefCell::new(None);
+    }
+
+    fn message(payload: Box<dyn ::std::any::Any + Send>) -> String {
+        payload
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_else(|| "unknown error".to_owned())
+    }
+
+    pub fn catch_unwind(f: impl FnOnce() -> TokenStream) -> TokenStream {
+        panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+            PANIC.with(|panic| *panic.borrow_mut() = Some(message(payload)));
+            TokenStream::new()
+        })
+    }
+
+    /// Run the export `func` on `input`, passing the result to `output`.
+    /// Returns 0 on success, with the output tokens prefixed by a line giving
+    /// the offset in `input` of each output token's span, or 1 with the
+    /// message of a panic.
+    #[no_mangle]
+    pub unsafe extern "C" fn __ctrs_native_run(
+        func: *const c_void,
+        input: *const u8,
+        input_len: usize,
+        output: unsafe extern "C" fn(*mut c_void, *const u8, usize),
+        ctx: *mut c_void,
+    ) -> u32 {
+        let func = ::std::mem::transmute::<*const c_void, extern "C" fn(TokenStream) -> TokenStream>(func);
+        let input = ::std::str::from_utf8_unchecked(::std::slice::from_raw_parts(input, input_len));
+
+        panic::set_hook(Box::new(|_| {}));
+        let result = panic::catch_unwind(|| {
+            let tokens = input.parse::<TokenStream>().expect("invalid input");
+            let file = tokens.clone().into_iter().next().map(|token| token.span());
+            let tokens = func(tokens);
+            if let Some(message) = PANIC.with(|panic| panic.borrow_mut().take()) {
+                return Err(message);
+            }
+            let mut printer = Printer {
+                input,
+                file,
+                lines: ::std::iter::once(0)
+                    .chain(input.match_indices('\n').map(|(idx, _)| idx + 1))
+                    .collect(),
+                text: String::new(),
+                offsets: String::new(),
+            };
+            printer.print(tokens);
+            Ok(format!("{}\n{}", printer.offsets, printer.text))
+        });
+        let _ = panic::take_hook();
+
+        let (status, text) = match result {
+            Ok(Ok(text)) => (0, text),
+            Ok(Err(message)) => (1, message),
+            Err(payload) => (1, message(payload)),
+        };
+        output(ctx, text.as_ptr(), text.len());
+        status
+    }
+
+    /// Prints tokens in the same way as the host, so that the host parses
+    /// them back to the same tree.
+    struct Printer<'a> {
+        input: &'a str,
+        /// The span of a token of the input, if it has any tokens.
+        file: Option<Span>,
+        lines: Vec<usize>,
+        text: String,
+        offsets: String,
+    }
+
+    impl Printer<'_> {
+        fn print(&mut self, stream: TokenStream) {
+            for token in stream {
+                match token {
+                    TokenTree::Group(group) => {
+                        let (open, close) = match group.delimiter() {
+                            Delimiter::Parenthesis => ("(", ")"),
+                            Delimiter::Brace => ("{", "}"),
+                            Delimiter::Bracket => ("[", "]"),
+                            // Invisible groups don't survive being printed.
+                            Delimiter::None => {
+                                self.print(group.stream());
+                                continue;
+                            }
+                        };
+                        self.offset(group.span());
+                        self.text.push_str(open);
+                        self.print(group.stream());
+                        self.text.push_str(close);
+                        self.text.push(' ');
+                    }
+                    TokenTree::Punct(punct) => {
+                        self.offset(punct.span());
+                        self.text.push(punct.as_char());
+                        if punct.spacing() == Spacing::Alone {
+                            self.text.push(' ');
+                        }
+                    }
+                    TokenTree::Ident(ident) => {
+                        self.offset(ident.span());
+                        self.text.push_str(&format!("{} ", ident));
+                    }
+                    TokenTree::Literal(literal) => {
+                        let text = literal.to_string();
+                        // Negative literals are parsed back as two tokens.
+                        if text.starts_with('-') {
+                            self.offset(literal.span());
+                        }
+                        self.offset(literal.span());
+                        self.text.push_str(&text);
+                        self.text.push(' ');
+                    }
+                }
+            }
+        }
+
+        /// Record the offset in the input of the start of `span`, or `-` if
+        /// it isn't from the input.
+        fn offset(&mut self, span: Span) {
+            // Spans join only with spans parsed from the same string, and
+            // spans of other strings have locations within those strings.
+            let start = span.start();
+            if self.file.and_then(|file| file.join(span)).is_none() || start.line > self.lines.len() {
+                self.offsets.push_str("- ");
+                return;
+            }
+            let line_start = self.lines[start.line - 1];
+            let line = &self.input[line_start..];
+            let column = line.char_indices().nth(start.column).map_or(line.len(), |(idx, _)| idx);
+            self.offsets.push_str(&format!("{} ", line_start + column));
+        }
+    }
+}
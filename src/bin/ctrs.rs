@@ -0,0 +1,94 @@
+//! Precompile a macro crate into a standalone `.wasm` file and manifest, which
+//! can be used without its source or dependencies.
+//!
+//! ```text
+//! ctrs <SOURCE> [--out-dir <DIR>]
+//! ```
+//!
+//! `SOURCE` holds the body of a `ctrs!` invocation, starting with the
+//! `macro crate` line. It is built by expanding `ctrs!` in a temporary crate,
+//! so that exactly the same processing is applied as for inline macro crates.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use tempdir::TempDir;
+
+const USAGE: &str = "usage: ctrs <SOURCE> [--out-dir <DIR>]";
+
+fn main() {
+    let mut source = None;
+    let mut out_dir = PathBuf::from(".");
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "-o" | "--out-dir" => match args.next() {
+                Some(dir) => out_dir = PathBuf::from(dir),
+                None => fail(USAGE),
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if source.is_none() && !arg.starts_with('-') => source = Some(PathBuf::from(arg)),
+            _ => fail(USAGE),
+        }
+    }
+    let source = source.unwrap_or_else(|| fail(USAGE));
+
+    if let Err(err) = build(&source, &out_dir) {
+        fail(&format!("error: {}", err));
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn build(source: &Path, out_dir: &Path) -> Result<(), String> {
+    let source = fs::canonicalize(source)
+        .map_err(|err| format!("couldn't read `{}`: {}", source.display(), err))?;
+    let code = fs::read_to_string(&source)
+        .map_err(|err| format!("couldn't read `{}`: {}", source.display(), err))?;
+    fs::create_dir_all(out_dir).map_err(|err| err.to_string())?;
+    let out_dir = fs::canonicalize(out_dir).map_err(|err| err.to_string())?;
+
+    // The source starts on the first line of the crate, so that line numbers
+    // in diagnostics match up.
+    let tmp = TempDir::new("ctrs_cli").map_err(|err| err.to_string())?;
+    let manifest = format!(
+        "[package]\n\
+         name = \"ctrs-precompile\"\n\
+         version = \"0.0.0\"\n\
+         edition = \"2018\"\n\
+         \n\
+         [lib]\n\
+         path = \"lib.rs\"\n\
+         \n\
+         [dependencies]\n\
+         ctrs = {{ path = {:?} }}\n\
+         \n\
+         [workspace]\n",
+        env!("CARGO_MANIFEST_DIR"),
+    );
+    fs::write(tmp.path().join("Cargo.toml"), manifest).map_err(|err| err.to_string())?;
+    fs::write(tmp.path().join("lib.rs"), format!("ctrs::ctrs! {{ {}\n}}\n", code))
+        .map_err(|err| err.to_string())?;
+
+    // Share a target directory between runs, so that `ctrs` itself is only
+    // built once.
+    let status = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned()))
+        .args(["build", "--lib", "--manifest-path"])
+        .arg(tmp.path().join("Cargo.toml"))
+        .env("CARGO_TARGET_DIR", env::temp_dir().join("ctrs-precompile"))
+        .env("CTRS_EMIT_DIR", &out_dir)
+        .env("CTRS_SOURCE_DIR", source.parent().unwrap())
+        .status()
+        .map_err(|err| format!("couldn't run cargo: {}", err))?;
+    if !status.success() {
+        return Err(format!("couldn't build `{}`", source.display()));
+    }
+    Ok(())
+}
@@ -0,0 +1,168 @@
+//! Persistent, content-addressed cache of compiled macro crates.
+//!
+//! Entries are stored as `$CACHE_DIR/$KEY.wasm`, where `$KEY` is a hash of
+//! everything which can affect the output of `rustc`. Diagnostics emitted
+//! while building the module, if any, are stored in `$CACHE_DIR/$KEY.json`.
+//! Modules are also kept by the hash of their contents as
+//! `$CACHE_DIR/modules/$HASH.wasm`, so that invocations of their macros can be
+//! run by processes which haven't built or loaded them. The cache may be
+//! shared between concurrently running `rustc` processes, so entries are
+//! written to a temporary file and atomically renamed into place.
+
+use sha2::{Digest, Sha256};
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::SystemTime;
+
+/// Default cap on the total size of cached modules, in bytes.
+const DEFAULT_LIMIT: u64 = 256 * 1024 * 1024;
+
+pub struct Cache {
+    dir: PathBuf,
+    limit: u64,
+}
+
+impl Cache {
+    /// Open the cache directory. Returns `None` if caching is disabled by
+    /// setting `CTRS_CACHE_DIR` to the empty string.
+    ///
+    /// The cache lives in `ctrs-cache` within the target directory unless
+    /// `CTRS_CACHE_DIR` is set, and is limited to `CTRS_CACHE_SIZE` bytes.
+    pub fn open() -> Option<Cache> {
+        let dir = match env::var_os("CTRS_CACHE_DIR") {
+            Some(dir) if dir.is_empty() => return None,
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(env!("CTRS_DEFAULT_CACHE_DIR")),
+        };
+        let limit = env::var("CTRS_CACHE_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(DEFAULT_LIMIT);
+        fs::create_dir_all(&dir).ok()?;
+        Some(Cache { dir, limit })
+    }
+
+    /// Compute the cache key for a set of inputs.
+    pub fn key(parts: &[&[u8]]) -> String {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            // Length-prefix each part so that boundaries are unambiguous.
+            hasher.input((part.len() as u64).to_le_bytes());
+            hasher.input(part);
+        }
+        hasher
+            .result()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Directory in which additional dependencies of macro crates are built.
+    /// Dependency builds are small and few, so are not evicted.
+    pub fn deps_dir(&self) -> PathBuf {
+        self.dir.join("deps")
+    }
+
+    /// Directory in which native builds of macro crates are kept, by the hash
+    /// of their wasm module. These are not evicted either, as they may be
+    /// loaded by running compilers.
+    #[cfg(feature = "native")]
+    pub fn native_dir(&self) -> PathBuf {
+        self.dir.join("native")
+    }
+
+    fn modules_dir(&self) -> PathBuf {
+        self.dir.join("modules")
+    }
+
+    fn path(&self, key: &str, ext: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", key, ext))
+    }
+
+    /// Look up a cached module and its diagnostics, marking it as recently
+    /// used.
+    pub fn get(&self, key: &str) -> Option<(Vec<u8>, String)> {
+        let path = self.path(key, "wasm");
+        let wasm = fs::read(&path).ok()?;
+        let diagnostics = fs::read_to_string(self.path(key, "json")).unwrap_or_default();
+        if let Ok(file) = File::options().write(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Some((wasm, diagnostics))
+    }
+
+    /// Add a module to the cache, evicting the least recently used entries
+    /// if the cache has grown beyond its size limit.
+    pub fn insert(&self, key: &str, wasm: &[u8], diagnostics: &str) -> io::Result<()> {
+        // Diagnostics are written first, so they are present whenever the
+        // module is.
+        if !diagnostics.is_empty() {
+            self.write(&self.path(key, "json"), diagnostics.as_bytes())?;
+        }
+        self.write(&self.path(key, "wasm"), wasm)?;
+        self.evict()
+    }
+
+    /// Look up a module by the hash of its contents.
+    pub fn module(&self, hash: &str) -> Option<Vec<u8>> {
+        fs::read(self.modules_dir().join(format!("{}.wasm", hash))).ok()
+    }
+
+    /// Keep a loaded module by the hash of its contents, if it isn't kept
+    /// already.
+    pub fn insert_module(&self, hash: &str, wasm: &[u8]) -> io::Result<()> {
+        let path = self.modules_dir().join(format!("{}.wasm", hash));
+        if path.is_file() {
+            return Ok(());
+        }
+        fs::create_dir_all(self.modules_dir())?;
+        self.write(&path, wasm)?;
+        self.evict()
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(format!(".{}.tmp", process::id()));
+        fs::write(&tmp, data)?;
+        if let Err(err) = fs::rename(&tmp, path) {
+            let _ = fs::remove_file(&tmp);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn evict(&self) -> io::Result<()> {
+        let mut entries = Vec::new();
+        let mut total = 0;
+        let modules = fs::read_dir(self.modules_dir()).into_iter().flatten();
+        for entry in fs::read_dir(&self.dir)?.chain(modules) {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension() != Some(OsStr::new("wasm")) {
+                continue;
+            }
+            // Entries may be concurrently removed by another process.
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            total += meta.len();
+            entries.push((meta.modified()?, meta.len(), path));
+        }
+
+        entries.sort();
+        for (_, len, path) in entries {
+            if total <= self.limit {
+                break;
+            }
+            let _ = fs::remove_file(&path);
+            let _ = fs::remove_file(path.with_extension("json"));
+            total -= len;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+//! Additional dependencies of macro crates, declared in the `macro crate`
+//! header and built from local source for `wasm32-unknown-unknown`.
+
+use crate::cache::Cache;
+use crate::diagnostics;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tempdir::TempDir;
+
+/// A dependency which has been built for `wasm32-unknown-unknown`.
+pub struct Dependency {
+    pub name: String,
+    /// Path to the built rlib.
+    pub rlib: PathBuf,
+    /// Cache key of the build, which identifies the dependency's source and
+    /// those of its own dependencies.
+    pub key: String,
+    /// Source files which the dependency was built from.
+    pub files: Vec<PathBuf>,
+}
+
+/// Build the dependency `name` from the crate directory at `path`, relative
+/// to the invoking crate's manifest directory. Previously built dependencies
+/// in `deps` are available to it, along with the runtime crates.
+///
+/// Builds are stored in `out_dir` under their cache key, and re-used if
+/// present.
+pub fn build(name: &str, path: &str, deps: &[Dependency], out_dir: &Path) -> Result<Dependency, String> {
+    let root = crate::source_dir().join(path);
+    let lib = root.join("src").join("lib.rs");
+    if !lib.is_file() {
+        return Err(format!("no library crate found at `{}`", root.display()));
+    }
+
+    let mut files = Vec::new();
+    collect_files(&root.join("src"), &mut files).map_err(|err| err.to_string())?;
+    let manifest = root.join("Cargo.toml");
+    let edition = if manifest.is_file() {
+        files.push(manifest.clone());
+        read_edition(&manifest)?
+    } else {
+        "2015".to_owned()
+    };
+    files.sort();
+
+    let mut parts = vec![
+        name.as_bytes().to_owned(),
+        env!("CTRS_RUSTC_VERSION").as_bytes().to_owned(),
+        env!("CTRS_RUNTIME_HASH").as_bytes().to_owned(),
+    ];
+    for dep in deps {
+        parts.push(dep.key.as_bytes().to_owned());
+    }
+    for file in &files {
+        let relative = file.strip_prefix(&root).unwrap_or(file);
+        parts.push(relative.to_string_lossy().as_bytes().to_owned());
+        parts.push(fs::read(file).map_err(|err| err.to_string())?);
+    }
+    let key = Cache::key(&parts.iter().map(|part| &part[..]).collect::<Vec<_>>());
+
+    let dir = out_dir.join(&key);
+    let rlib = dir.join(format!("lib{}.rlib", name));
+    if !rlib.is_file() {
+        run_rustc(name, &lib, &edition, deps, out_dir, &dir)?;
+    }
+
+    Ok(Dependency {
+        name: name.to_owned(),
+        rlib,
+        key,
+        files,
+    })
+}
+
+/// Read the edition of a crate from its manifest.
+fn read_edition(manifest: &Path) -> Result<String, String> {
+    let manifest = fs::read_to_string(manifest).map_err(|err| err.to_string())?;
+    let manifest = manifest.parse::<toml::Value>().map_err(|err| err.to_string())?;
+    Ok(manifest
+        .get("package")
+        .and_then(|package| package.get("edition"))
+        .and_then(|edition| edition.as_str())
+        .unwrap_or("2015")
+        .to_owned())
+}
+
+/// Find the Rust source files within `dir`.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path.extension() == Some(OsStr::new("rs")) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Build a dependency into `dir`. The rlib is built in a temporary directory
+/// and renamed into place, as other processes may be building it too.
+fn run_rustc(
+    name: &str,
+    lib: &Path,
+    edition: &str,
+    deps: &[Dependency],
+    out_dir: &Path,
+    dir: &Path,
+) -> Result<(), String> {
+    fs::create_dir_all(out_dir).map_err(|err| err.to_string())?;
+    let tmp = TempDir::new_in(out_dir, "build").map_err(|err| err.to_string())?;
+
+    let mut cmd = Command::new(env!("RUSTC"));
+    cmd.stdin(Stdio::null())
+        .stderr(Stdio::piped())
+        .args([
+            "--target",
+            "wasm32-unknown-unknown",
+            "--crate-type",
+            "rlib",
+            "--cap-lints",
+            "allow",
+            "--error-format",
+            "json",
+        ])
+        .args(["--edition", edition, "--crate-name", name])
+        .arg("--out-dir")
+        .arg(tmp.path())
+        .args(crate::runtime_args(tmp.path()).map_err(|err| err.to_string())?);
+    for dep in deps {
+        cmd.arg("--extern")
+            .arg(format!("{}={}", dep.name, dep.rlib.display()))
+            .arg("-L")
+            .arg(format!("dependency={}", dep.rlib.parent().unwrap().display()));
+    }
+    let output = cmd.arg(lib).output().map_err(|err| err.to_string())?;
+
+    if !output.status.success() {
+        let message = format!(
+            "dependency `{}` could not be built for wasm32-unknown-unknown",
+            name
+        );
+        return Err(message + &diagnostics::rendered_errors(&output.stderr));
+    }
+
+    // Move the rlib into place. Another process may have finished building
+    // the dependency first, in which case its build is used.
+    let staged = tmp.path().join("staged");
+    fs::create_dir(&staged).map_err(|err| err.to_string())?;
+    let rlib = format!("lib{}.rlib", name);
+    fs::rename(tmp.path().join(&rlib), staged.join(&rlib)).map_err(|err| err.to_string())?;
+    if fs::rename(&staged, dir).is_err() && !dir.is_dir() {
+        return Err(format!("failed to store dependency `{}`", name));
+    }
+    Ok(())
+}
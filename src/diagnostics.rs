@@ -0,0 +1,526 @@
+//! Mapping of diagnostics from macro crate builds back to the tokens of the
+//! `ctrs!` invocation.
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+use serde_json::Value;
+
+/// Number of tokens in a source file which are searched for each token of the
+/// macro crate source, when matching the two up. This allows for the tokens
+/// of the attributes which are removed from macro functions.
+const ALIGN_WINDOW: usize = 32;
+
+/// Source code for a macro crate, along with the span of the token which
+/// produced each range of bytes.
+pub struct SourceMap {
+    pub source: String,
+    spans: Vec<(usize, usize, Span)>,
+    /// Files the macro crate was loaded from.
+    files: Vec<SourceFile>,
+    /// For each token in `spans`, the file it was loaded from, if any, and its
+    /// offset in that file if it could be found.
+    locations: Vec<Option<(usize, Option<usize>)>>,
+}
+
+impl SourceMap {
+    pub fn new(stream: TokenStream) -> Self {
+        SourceMap::with_files(stream, Vec::new())
+    }
+
+    /// Build a source map for a macro crate loaded from `files`. Tokens are
+    /// from the root file, the one without a key, unless they're within an
+    /// out-of-line module marked with `#![__ctrs_file = "key"]`.
+    ///
+    /// Tokens parsed from a string have no useful spans, so the tokens of the
+    /// macro crate are instead matched up with those in their file, in order.
+    /// Tokens which aren't found nearby, such as those of generated items,
+    /// are left unmatched.
+    pub fn with_files(stream: TokenStream, files: Vec<SourceFile>) -> Self {
+        let mut map = SourceMap {
+            source: String::new(),
+            spans: Vec::new(),
+            files,
+            locations: Vec::new(),
+        };
+        let root = map.files.iter().position(|file| file.key.is_none());
+        map.print_stream(stream, root);
+
+        let mut cursors = vec![0; map.files.len()];
+        for (idx, &(start, end, _)) in map.spans.iter().enumerate() {
+            if let Some((file, offset)) = &mut map.locations[idx] {
+                let text = &map.source[start..end];
+                let tokens = &map.files[*file].tokens;
+                let cursor = &mut cursors[*file];
+                *offset = tokens[*cursor..]
+                    .iter()
+                    .take(ALIGN_WINDOW)
+                    .position(|(token, _)| token == text)
+                    .map(|found| {
+                        *cursor += found + 1;
+                        tokens[*cursor - 1].1
+                    });
+            }
+        }
+        map
+    }
+
+    fn print_stream(&mut self, stream: TokenStream, file: Option<usize>) {
+        for token in stream {
+            match token {
+                TokenTree::Group(group) => {
+                    let (open, close) = match group.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::None => ("", ""),
+                    };
+                    self.print(open, group.span(), file);
+                    let mut inner = group.stream();
+                    let mut inner_file = file;
+                    if let Some((key, rest)) = file_marker(&inner) {
+                        inner = rest;
+                        inner_file = self.files.iter().position(|file| file.key.as_ref() == Some(&key));
+                    }
+                    self.print_stream(inner, inner_file);
+                    self.print(close, group.span(), file);
+                    self.source.push(' ');
+                }
+                TokenTree::Punct(punct) => {
+                    self.print(&punct.to_string(), punct.span(), file);
+                    if punct.spacing() == Spacing::Alone {
+                        self.source.push(' ');
+                    }
+                }
+                TokenTree::Ident(ident) => {
+                    self.print(&ident.to_string(), ident.span(), file);
+                    self.source.push(' ');
+                }
+                TokenTree::Literal(literal) => {
+                    self.print(&literal.to_string(), literal.span(), file);
+                    self.source.push(' ');
+                }
+            }
+        }
+    }
+
+    fn print(&mut self, text: &str, span: Span, file: Option<usize>) {
+        let start = self.source.len();
+        self.source.push_str(text);
+        self.spans.push((start, self.source.len(), span));
+        self.locations.push(file.map(|file| (file, None)));
+    }
+
+    /// Find the span of the token containing the given byte offset.
+    #[cfg(feature = "native")]
+    pub fn span_at(&self, offset: usize) -> Option<Span> {
+        self.lookup(offset).map(|idx| self.spans[idx].2)
+    }
+
+    /// Find the index of the token containing the given byte offset.
+    fn lookup(&self, offset: usize) -> Option<usize> {
+        match self.spans.binary_search_by_key(&offset, |&(start, _, _)| start) {
+            Ok(idx) => Some(idx),
+            Err(0) => None,
+            Err(idx) => Some(idx - 1),
+        }
+    }
+
+    /// Lower a JSON diagnostic emitted by rustc to tokens reporting it at the
+    /// corresponding location in the `ctrs!` invocation. Errors are reported
+    /// with `compile_error!`, and warnings with a use of a deprecated item.
+    pub fn lower(&self, diagnostic: &Value) -> TokenStream {
+        let level = diagnostic["level"].as_str().unwrap_or_default();
+        let message = diagnostic["message"].as_str().unwrap_or_default();
+        if (level != "error" && level != "warning") || message.starts_with("aborting due to") {
+            return TokenStream::new();
+        }
+
+        let primary = diagnostic["spans"]
+            .as_array()
+            .and_then(|spans| spans.iter().find(|span| span["is_primary"] == true));
+        // Warnings without a location, such as the "N warnings emitted"
+        // summary, say nothing about the macro crate.
+        if level == "warning" && primary.is_none() {
+            return TokenStream::new();
+        }
+        let (start, end) = match primary.and_then(local_span) {
+            Some(span) => {
+                let byte_start = span["byte_start"].as_u64().unwrap_or_default() as usize;
+                let byte_end = span["byte_end"].as_u64().unwrap_or_default() as usize;
+                let start = self.lookup(byte_start);
+                let end = self.lookup(byte_end.saturating_sub(1).max(byte_start)).or(start);
+                (start, end)
+            }
+            None => (None, None),
+        };
+
+        let mut text = message.to_owned();
+        if let Some(label) = primary.and_then(|span| span["label"].as_str()) {
+            text.push_str(&format!("\n{}", label));
+        }
+
+        // Diagnostics in a source file can only be reported at the tokens
+        // which loaded the file, so describe the location in the text.
+        let (start, end) = match start.and_then(|idx| self.locations[idx]) {
+            Some((file, offset)) => {
+                let file = &self.files[file];
+                if let Some(offset) = offset {
+                    text.push_str(&file.location(offset));
+                }
+                (file.span, file.span)
+            }
+            None => {
+                let start = start.map_or_else(Span::call_site, |idx| self.spans[idx].2);
+                let end = end.map_or(start, |idx| self.spans[idx].2);
+                (start, end)
+            }
+        };
+        for child in diagnostic["children"].as_array().into_iter().flatten() {
+            let child_level = child["level"].as_str().unwrap_or_default();
+            let child_message = child["message"].as_str().unwrap_or_default();
+            text.push_str(&format!("\n= {}: {}", child_level, child_message));
+        }
+
+        if level == "error" {
+            compile_error(start, end, &text)
+        } else {
+            warning(start, &text)
+        }
+    }
+}
+
+/// A file containing the source of a macro crate, or of one of its modules.
+pub struct SourceFile {
+    /// Key of an out-of-line module's file, or `None` for the crate root.
+    key: Option<String>,
+    /// Path of the file, relative to the invoking crate's manifest directory.
+    path: String,
+    text: String,
+    /// Span of the tokens in the `ctrs!` invocation which loaded the file.
+    span: Span,
+    /// The text of each token in the file, and its offset.
+    tokens: Vec<(String, usize)>,
+}
+
+impl SourceFile {
+    /// Record the location of each token in `stream`, which was parsed from
+    /// `text`.
+    pub fn new(
+        key: Option<String>,
+        path: String,
+        text: String,
+        span: Span,
+        stream: TokenStream,
+    ) -> Self {
+        let mut file = SourceFile {
+            key,
+            path,
+            text,
+            span,
+            tokens: Vec::new(),
+        };
+        let mut pos = 0;
+        file.locate_stream(stream, &mut pos);
+        file
+    }
+
+    fn locate_stream(&mut self, stream: TokenStream, pos: &mut usize) {
+        let mut doc_comment = None;
+        for token in stream {
+            if doc_comment.is_none() {
+                *pos = skip_trivia(&self.text, *pos);
+            }
+
+            // Doc comments are parsed as `#[doc = ".."]` attributes, all of
+            // whose tokens are located at the comment.
+            if let TokenTree::Punct(punct) = &token {
+                if punct.as_char() == '#' && doc_comment.is_none() {
+                    if let Some(len) = doc_comment_len(&self.text[*pos..]) {
+                        doc_comment = Some(*pos);
+                        *pos += len;
+                    }
+                }
+            }
+            if let Some(offset) = doc_comment {
+                let is_attr = matches!(&token, TokenTree::Group(_));
+                self.locate_all(token, offset);
+                if is_attr {
+                    doc_comment = None;
+                }
+                continue;
+            }
+
+            match token {
+                TokenTree::Group(group) => {
+                    let (open, close) = match group.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::None => ("", ""),
+                    };
+                    self.locate(open, pos);
+                    self.locate_stream(group.stream(), pos);
+                    *pos = skip_trivia(&self.text, *pos);
+                    self.locate(close, pos);
+                }
+                token => self.locate(&token.to_string(), pos),
+            }
+        }
+    }
+
+    /// Record the token `text` at `pos`, and move past it if it's there.
+    fn locate(&mut self, text: &str, pos: &mut usize) {
+        self.tokens.push((text.to_owned(), *pos));
+        if self.text[*pos..].starts_with(text) {
+            *pos += text.len();
+        }
+    }
+
+    /// Record `token` and everything inside it at `offset`.
+    fn locate_all(&mut self, token: TokenTree, offset: usize) {
+        let map = SourceMap::new(token.into());
+        for &(start, end, _) in &map.spans {
+            self.tokens.push((map.source[start..end].to_owned(), offset));
+        }
+    }
+
+    /// Describe the location of `offset` in the file, along with the line of
+    /// source it's on.
+    fn location(&self, offset: usize) -> String {
+        let line_start = self.text[..offset].rfind('\n').map_or(0, |idx| idx + 1);
+        let line_end = self.text[offset..].find('\n').map_or(self.text.len(), |idx| offset + idx);
+        let line = self.text[..offset].matches('\n').count() + 1;
+        let column = self.text[line_start..offset].chars().count() + 1;
+        let number = line.to_string();
+        let gutter = " ".repeat(number.len());
+        format!(
+            "\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}^",
+            gutter,
+            self.path,
+            line,
+            column,
+            gutter,
+            number,
+            &self.text[line_start..line_end],
+            gutter,
+            " ".repeat(column - 1),
+        )
+    }
+}
+
+/// Check for the `#![__ctrs_file = "key"]` attribute which marks the contents
+/// of an out-of-line module, returning the key and the rest of the module.
+fn file_marker(stream: &TokenStream) -> Option<(String, TokenStream)> {
+    let mut iter = stream.clone().into_iter();
+    match (iter.next()?, iter.next()?, iter.next()?) {
+        (TokenTree::Punct(pound), TokenTree::Punct(bang), TokenTree::Group(attr))
+            if pound.as_char() == '#' && bang.as_char() == '!' =>
+        {
+            let mut attr = attr.stream().into_iter();
+            match (attr.next()?, attr.next()?, attr.next()?) {
+                (TokenTree::Ident(name), TokenTree::Punct(_), TokenTree::Literal(key))
+                    if name.to_string() == "__ctrs_file" =>
+                {
+                    let key = key.to_string();
+                    Some((key[1..key.len() - 1].to_owned(), iter.collect()))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Skip whitespace and non-doc comments in `text` from `pos`.
+fn skip_trivia(text: &str, mut pos: usize) -> usize {
+    loop {
+        let rest = &text[pos..];
+        let trimmed = rest.trim_start();
+        pos += rest.len() - trimmed.len();
+        if doc_comment_len(trimmed).is_some() {
+            return pos;
+        }
+        pos += match comment_len(trimmed) {
+            Some(len) => len,
+            None => return pos,
+        };
+    }
+}
+
+/// Length of the comment at the start of `text`, if any.
+fn comment_len(text: &str) -> Option<usize> {
+    if text.starts_with("//") {
+        return Some(text.find('\n').unwrap_or(text.len()));
+    }
+    if !text.starts_with("/*") {
+        return None;
+    }
+    // Block comments nest.
+    let mut depth = 0;
+    let mut pos = 0;
+    while pos < text.len() {
+        if text[pos..].starts_with("/*") {
+            depth += 1;
+            pos += 2;
+        } else if text[pos..].starts_with("*/") {
+            depth -= 1;
+            pos += 2;
+            if depth == 0 {
+                return Some(pos);
+            }
+        } else {
+            pos += text[pos..].chars().next().unwrap().len_utf8();
+        }
+    }
+    Some(text.len())
+}
+
+/// Length of the doc comment at the start of `text`, if any.
+fn doc_comment_len(text: &str) -> Option<usize> {
+    let is_doc = (text.starts_with("///") && !text.starts_with("////"))
+        || text.starts_with("//!")
+        || (text.starts_with("/**") && !text.starts_with("/***") && !text.starts_with("/**/"))
+        || text.starts_with("/*!");
+    if is_doc {
+        comment_len(text)
+    } else {
+        None
+    }
+}
+
+/// Find the span within the macro crate source which a diagnostic span was
+/// produced by, following macro expansions back from other files.
+fn local_span(mut span: &Value) -> Option<&Value> {
+    // rustc names source read from stdin `<anon>`.
+    while span["file_name"] != "<anon>" {
+        span = span.get("expansion")?.get("span")?;
+    }
+    Some(span)
+}
+
+/// The rendered text of each error among the JSON diagnostics printed by
+/// rustc, one after another, each on a new line.
+pub fn rendered_errors(stderr: &[u8]) -> String {
+    let mut errors = String::new();
+    for line in String::from_utf8_lossy(stderr).lines() {
+        if let Ok(diagnostic) = serde_json::from_str::<Value>(line) {
+            if diagnostic["level"] == "error" {
+                if let Some(rendered) = diagnostic["rendered"].as_str() {
+                    errors.push('\n');
+                    errors.push_str(rendered);
+                }
+            }
+        }
+    }
+    errors
+}
+
+/// Build a `compile_error!` invocation covering the tokens from `start` to
+/// `end`.
+pub fn compile_error(start: Span, end: Span, message: &str) -> TokenStream {
+    let mut bang = Punct::new('!', Spacing::Alone);
+    bang.set_span(start);
+    let mut literal = Literal::string(message);
+    literal.set_span(end);
+    let mut group = Group::new(Delimiter::Brace, TokenTree::Literal(literal).into());
+    group.set_span(end);
+
+    vec![
+        TokenTree::Ident(Ident::new("compile_error", start)),
+        TokenTree::Punct(bang),
+        TokenTree::Group(group),
+    ]
+    .into_iter()
+    .collect()
+}
+
+/// Level of a diagnostic which doesn't fail the build.
+pub enum Level {
+    Warning,
+    Note,
+    Help,
+}
+
+/// Emit a warning at `span`. See `emit`.
+pub fn warning(span: Span, message: &str) -> TokenStream {
+    emit(Level::Warning, span, message)
+}
+
+/// Emit a diagnostic at `span`.
+///
+/// There is no stable way for a proc macro to emit one directly, so this
+/// builds an item which causes rustc to emit a warning, with notes and help
+/// messages marked as such. On nightly, `proc_macro::Diagnostic` is used
+/// instead, and nothing is returned.
+#[cfg(not(ctrs_nightly))]
+pub fn emit(level: Level, span: Span, message: &str) -> TokenStream {
+    let message = match level {
+        Level::Warning => message.to_owned(),
+        Level::Note => format!("note: {}", message),
+        Level::Help => format!("help: {}", message),
+    };
+    let item = format!(
+        "const _: () = {{ \
+            #[deprecated(note = {})] \
+            #[allow(non_upper_case_globals)] \
+            const ctrs_warning: () = (); \
+            ctrs_warning \
+        }};",
+        Literal::string(&message),
+    );
+    respan(item.parse().unwrap(), span)
+}
+
+#[cfg(ctrs_nightly)]
+pub fn emit(level: Level, span: Span, message: &str) -> TokenStream {
+    let level = match level {
+        Level::Warning => proc_macro::Level::Warning,
+        Level::Note => proc_macro::Level::Note,
+        Level::Help => proc_macro::Level::Help,
+    };
+    proc_macro::Diagnostic::spanned(span, level, message).emit();
+    TokenStream::new()
+}
+
+#[cfg(not(ctrs_nightly))]
+fn respan(stream: TokenStream, span: Span) -> TokenStream {
+    stream
+        .into_iter()
+        .map(|mut token| {
+            if let TokenTree::Group(group) = &token {
+                let mut new_group = Group::new(group.delimiter(), respan(group.stream(), span));
+                new_group.set_span(span);
+                token = TokenTree::Group(new_group);
+            }
+            token.set_span(span);
+            token
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments() {
+        assert_eq!(comment_len("// line\nnext"), Some(7));
+        assert_eq!(comment_len("// last"), Some(7));
+        assert_eq!(comment_len("/* block */ next"), Some(11));
+        assert_eq!(comment_len("/* a /* nested */ b */ next"), Some(22));
+        assert_eq!(comment_len("/* a /* unterminated */"), Some(23));
+        assert_eq!(comment_len("/* é */"), Some(8));
+        assert_eq!(comment_len("/ not"), None);
+    }
+
+    #[test]
+    fn doc_comments() {
+        assert_eq!(doc_comment_len("/// doc\nnext"), Some(7));
+        assert_eq!(doc_comment_len("//! inner\nnext"), Some(9));
+        assert_eq!(doc_comment_len("/** a /* nested */ b */ next"), Some(23));
+        assert_eq!(doc_comment_len("/*! inner */"), Some(12));
+        assert_eq!(doc_comment_len("//// not doc"), None);
+        assert_eq!(doc_comment_len("/*** not doc */"), None);
+        assert_eq!(doc_comment_len("/**/"), None);
+        assert_eq!(doc_comment_len("// plain"), None);
+    }
+}
@@ -0,0 +1,315 @@
+//! Data about the invoking crate which macros may read through the
+//! `ctrs_host` module of their macro crate, as they can't access the
+//! filesystem or environment themselves.
+//!
+//! It is passed ahead of the macro's input, as
+//! `__ctrs_host__ [file "path" "/abs/path" b"contents", allow "PATTERN",
+//! env "NAME" "value", ..]`, where the `ctrs_host` module strips it off
+//! again. Nothing is added if there is nothing to pass.
+//!
+//! In turn, the output of a macro may start with
+//! `__ctrs_output__ position [tracking] [warning span "message", ..]`,
+//! holding the items which track what it read, and diagnostics it emitted.
+//! `position` is `items` for derive macros, and `any` for others, whose
+//! output may only be added to where they place an `__ctrs_items__` marker.
+
+use crate::diagnostics::{self, Level};
+use proc_macro::{Delimiter, Group, Ident, Literal, Span, TokenStream, TokenTree};
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Environment variables which macros may read, in addition to those listed
+/// in `CTRS_ENV_ALLOW`. See `pattern_matches`.
+const ALLOWED_VARS: &[&str] = &["CARGO_PKG_*", "CARGO_MANIFEST_DIR", "CARGO_CRATE_NAME", "OUT_DIR"];
+
+/// Add the data which the macro may read to its input.
+///
+/// Macros may read allowed environment variables, and files within the
+/// invoking crate's directory which are named by a string literal in their
+/// input, if the path matches one of the patterns in `read_files`.
+pub fn provide(input: TokenStream, read_files: &[String]) -> TokenStream {
+    let mut entries = TokenStream::new();
+    let mut entry = |tokens: Vec<TokenTree>| {
+        entries.extend(tokens);
+        entries.extend(",".parse::<TokenStream>().unwrap());
+    };
+
+    let root = if read_files.is_empty() {
+        None
+    } else {
+        env::var_os("CARGO_MANIFEST_DIR").and_then(|dir| fs::canonicalize(dir).ok())
+    };
+    if let Some(root) = root {
+        let mut paths = Vec::new();
+        string_literals(input.clone(), &mut paths);
+        paths.retain(|path| read_files.iter().any(|pattern| pattern_matches(pattern, path)));
+        paths.sort();
+        paths.dedup();
+        for path in paths {
+            if let Some((abs, contents)) = read_file(&root, &path) {
+                entry(vec![
+                    TokenTree::Ident(Ident::new("file", Span::call_site())),
+                    TokenTree::Literal(Literal::string(&path)),
+                    TokenTree::Literal(Literal::string(&abs)),
+                    TokenTree::Literal(Literal::byte_string(&contents)),
+                ]);
+            }
+        }
+    }
+
+    // Variables which are allowed but unset are still tracked, so the
+    // patterns are passed along with the variables.
+    let allowed = allowed_vars();
+    for pattern in &allowed {
+        entry(vec![
+            TokenTree::Ident(Ident::new("allow", Span::call_site())),
+            TokenTree::Literal(Literal::string(pattern)),
+        ]);
+    }
+    for (name, value) in env::vars_os() {
+        if let (Some(name), Some(value)) = (name.to_str(), value.to_str()) {
+            if allowed.iter().any(|pattern| pattern_matches(pattern, name)) {
+                entry(vec![
+                    TokenTree::Ident(Ident::new("env", Span::call_site())),
+                    TokenTree::Literal(Literal::string(name)),
+                    TokenTree::Literal(Literal::string(value)),
+                ]);
+            }
+        }
+    }
+
+    if entries.is_empty() {
+        return input;
+    }
+    let mut result = TokenStream::new();
+    result.extend(vec![
+        TokenTree::Ident(Ident::new("__ctrs_host__", Span::call_site())),
+        TokenTree::Group(Group::new(Delimiter::Bracket, entries)),
+    ]);
+    result.extend(input);
+    result
+}
+
+/// Remove the tracking items and diagnostics from the start of the output of
+/// the macro `name` from the macro crate `krate`, and add them back where
+/// items are expected.
+///
+/// They replace the first `__ctrs_items__` marker in the output, or if there
+/// is none, are added after output in item position. Otherwise, as dropping
+/// tracking would silently stop the invoking crate from being rebuilt when
+/// what the macro read changes, and dropping diagnostics would hide them on
+/// stable, an error telling the macro's author to place `ctrs_host::items()`
+/// is returned instead.
+pub fn finish(krate: &str, name: &str, output: TokenStream) -> Result<TokenStream, String> {
+    let mut iter = output.clone().into_iter();
+    let (position, tracking, emitted) = match (iter.next(), iter.next(), iter.next(), iter.next()) {
+        (
+            Some(TokenTree::Ident(marker)),
+            Some(TokenTree::Ident(position)),
+            Some(TokenTree::Group(tracking)),
+            Some(TokenTree::Group(emitted)),
+        ) if marker.to_string() == "__ctrs_output__" => {
+            (position.to_string(), tracking.stream(), emitted.stream())
+        }
+        _ => return Ok(output),
+    };
+    let mut read = tracked(tracking.clone(), "include_bytes")
+        .into_iter()
+        .map(|file| format!("the file `{}`", file))
+        .collect::<Vec<_>>();
+    read.extend(
+        tracked(tracking.clone(), "option_env")
+            .into_iter()
+            .map(|var| format!("the environment variable `{}`", var)),
+    );
+    read.sort();
+    read.dedup();
+
+    // Diagnostics which are shown through items, as on stable.
+    let mut shown = Vec::new();
+    let mut items = tracking;
+    let mut entry = emitted.into_iter();
+    while let (Some(level), Some(span), Some(message)) = (entry.next(), entry.next(), entry.next()) {
+        let kind = level.to_string();
+        let level = match &kind[..] {
+            "note" => Level::Note,
+            "help" => Level::Help,
+            _ => Level::Warning,
+        };
+        let message = string_value(&message.to_string()).unwrap_or_default();
+        let emitted = diagnostics::emit(level, span.span(), &message);
+        if !emitted.is_empty() {
+            shown.push(format!("the {} {:?}", kind, message));
+        }
+        items.extend(emitted);
+        entry.next();
+    }
+
+    let mut items = Some(items);
+    let output = place_items(iter.collect(), &mut items);
+    match (items, &position[..]) {
+        (Some(items), "items") => Ok(output.into_iter().chain(items).collect()),
+        (Some(_), _) if !read.is_empty() || !shown.is_empty() => {
+            let mut needed = Vec::new();
+            if !read.is_empty() {
+                needed.push(format!(
+                    "track {}, so that the invoking crate is rebuilt when they change",
+                    read.join(", ")
+                ));
+            }
+            if !shown.is_empty() {
+                needed.push(format!("show {}", shown.join(", ")));
+            }
+            Err(format!(
+                "proc macro `{}` from macro crate `{}` must place `ctrs_host::items()` in its \
+                 output where items are allowed, to {}",
+                name,
+                krate,
+                needed.join(", and to ")
+            ))
+        }
+        _ => Ok(output),
+    }
+}
+
+/// The values of the string literals passed to each invocation of the macro
+/// `mac` within the tracking items `stream`, such as the paths passed to
+/// `include_bytes!`.
+fn tracked(stream: TokenStream, mac: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut iter = stream.into_iter();
+    while let Some(token) = iter.next() {
+        if let TokenTree::Ident(ident) = token {
+            if ident.to_string() == mac {
+                if let (Some(TokenTree::Punct(_)), Some(TokenTree::Group(args))) = (iter.next(), iter.next()) {
+                    string_literals(args.stream(), &mut values);
+                }
+            }
+        }
+    }
+    values
+}
+
+/// Replace the first `__ctrs_items__` marker within `stream` with `items`,
+/// taking them, and remove any others.
+fn place_items(stream: TokenStream, items: &mut Option<TokenStream>) -> TokenStream {
+    let mut result = TokenStream::new();
+    for token in stream {
+        match token {
+            TokenTree::Ident(ident) if ident.to_string() == "__ctrs_items__" => {
+                result.extend(items.take());
+            }
+            TokenTree::Group(group) => {
+                let mut new_group = Group::new(group.delimiter(), place_items(group.stream(), items));
+                new_group.set_span(group.span());
+                result.extend(Some(TokenTree::Group(new_group)));
+            }
+            token => result.extend(Some(token)),
+        }
+    }
+    result
+}
+
+/// Patterns of the environment variables which macros may read.
+fn allowed_vars() -> Vec<String> {
+    let extra = env::var("CTRS_ENV_ALLOW").unwrap_or_default();
+    ALLOWED_VARS
+        .iter()
+        .map(|pattern| pattern.to_string())
+        .chain(extra.split(',').map(|pattern| pattern.trim().to_owned()))
+        .filter(|pattern| !pattern.is_empty())
+        .collect()
+}
+
+/// Whether `name`, of a variable or file, matches `pattern`. Patterns ending
+/// in `*` match any name with that prefix.
+fn pattern_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    }
+}
+
+/// Read the file at `path` relative to `root`, unless it is outside of
+/// `root`, returning its absolute path and contents.
+fn read_file(root: &Path, path: &str) -> Option<(String, Vec<u8>)> {
+    let abs = fs::canonicalize(root.join(path)).ok()?;
+    if !abs.starts_with(root) || !abs.is_file() {
+        return None;
+    }
+    let contents = fs::read(&abs).ok()?;
+    Some((abs.to_str()?.to_owned(), contents))
+}
+
+/// Collect the values of string literals within `stream`.
+fn string_literals(stream: TokenStream, values: &mut Vec<String>) {
+    for token in stream {
+        match token {
+            TokenTree::Group(group) => string_literals(group.stream(), values),
+            TokenTree::Literal(literal) => values.extend(string_value(&literal.to_string())),
+            _ => {}
+        }
+    }
+}
+
+/// The value of a string literal, given its source, or `None` if it isn't
+/// a string literal. Line continuations aren't supported.
+pub fn string_value(text: &str) -> Option<String> {
+    if let Some(raw) = text.strip_prefix('r') {
+        let raw = raw.trim_matches('#');
+        return Some(raw.strip_prefix('"')?.strip_suffix('"')?.to_owned());
+    }
+    let mut value = String::new();
+    let mut chars = text.strip_prefix('"')?.strip_suffix('"')?.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => value.push('\n'),
+            't' => value.push('\t'),
+            'r' => value.push('\r'),
+            '0' => value.push('\0'),
+            c @ ('\\' | '"' | '\'') => value.push(c),
+            'x' => {
+                let hex = chars.by_ref().take(2).collect::<String>();
+                value.push(char::from(u8::from_str_radix(&hex, 16).ok()?));
+            }
+            'u' => {
+                let hex = chars.by_ref().skip(1).take_while(|&c| c != '}').collect::<String>();
+                value.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+            }
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn string_values() {
+        assert_eq!(string_value(r#""plain""#).unwrap(), "plain");
+        assert_eq!(string_value(r#""a\n\t\r\0\\\"\'b""#).unwrap(), "a\n\t\r\0\\\"'b");
+        assert_eq!(string_value(r#""\x41\u{1F600}""#).unwrap(), "A\u{1F600}");
+        assert_eq!(string_value(r#""\q""#), None);
+        assert_eq!(string_value(r#""\u{110000}""#), None);
+    }
+
+    #[test]
+    fn raw_string_values() {
+        assert_eq!(string_value(r#"r"a\n""#).unwrap(), r"a\n");
+        assert_eq!(string_value(r###"r##"say "hi"#"##"###).unwrap(), r##"say "hi"#"##);
+    }
+
+    #[test]
+    fn other_literals() {
+        assert_eq!(string_value("1u8"), None);
+        assert_eq!(string_value("'c'"), None);
+        assert_eq!(string_value(r#"b"bytes""#), None);
+    }
+}
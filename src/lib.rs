@@ -1,14 +1,42 @@
+#![cfg_attr(ctrs_nightly, feature(proc_macro_diagnostic))]
+
 extern crate proc_macro;
 
-use proc_macro::{TokenStream, TokenTree};
+use proc_macro::{Delimiter, Group, Ident, Literal, Span, TokenStream, TokenTree};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::fs;
 use std::io::{self, Write};
 use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::Mutex;
 use std::env;
 use tempdir::TempDir;
 
+mod cache;
+mod deps;
+mod diagnostics;
+mod host;
+mod limits;
+mod manifest;
+#[cfg(feature = "native")]
+mod native;
+mod stderr;
+
+use crate::cache::Cache;
+use crate::deps::Dependency;
+use crate::diagnostics::{SourceFile, SourceMap};
+use crate::limits::Limits;
+
 // Crates provided as part of the runtime
+const RUNTIME_RLIBS: &[(&str, &[u8])] = &[
+    ("unicode_xid", UNICODE_XID_RLIB),
+    ("proc_macro2", PROC_MACRO2_RLIB),
+    ("syn", SYN_RLIB),
+    ("quote", QUOTE_RLIB),
+];
 const UNICODE_XID_RLIB: &[u8] = include_bytes!(concat!(
     env!("OUT_DIR"),
     "/wasm32-unknown-unknown/release/libunicode_xid.rlib"
@@ -32,62 +60,752 @@ const IMPL_WA: &[u8] = include_bytes!(concat!(
     "/wasm32-unknown-unknown/release/ctrs_wasm.wasm"
 ));
 
+// Flags passed to rustc which affect the compiled module, in addition to
+// dependency paths and resource limits.
+const RUSTC_FLAGS: &[&str] = &[
+    "--target",
+    "wasm32-unknown-unknown",
+    "--edition",
+    "2018",
+    "--crate-type",
+    "cdylib",
+];
+
+/// All flags passed to rustc which affect the compiled module, other than
+/// dependency paths.
+fn rustc_flags() -> io::Result<Vec<String>> {
+    let limits = Limits::from_env().map_err(|message| io::Error::new(io::ErrorKind::InvalidInput, message))?;
+    let mut flags = RUSTC_FLAGS.iter().map(|flag| flag.to_string()).collect::<Vec<_>>();
+    flags.extend(limits.rustc_flags());
+    Ok(flags)
+}
+
+/// The result of building a macro crate.
+struct Build {
+    /// The compiled module, or `None` if compilation failed.
+    wasm: Option<Vec<u8>>,
+    /// JSON diagnostics emitted by rustc, one per line.
+    diagnostics: String,
+}
+
+/// Build a macro crate, re-using a previously compiled module from the cache
+/// if nothing which could affect the build has changed.
+fn build_code(name: &str, source: &str, deps: &[Dependency]) -> io::Result<Build> {
+    let cache = Cache::open();
+    let flags = rustc_flags()?;
+    let flags = flags.join(" ");
+    let mut parts = vec![
+        name.as_bytes(),
+        source.as_bytes(),
+        flags.as_bytes(),
+        env!("CTRS_RUSTC_VERSION").as_bytes(),
+        env!("CTRS_RUNTIME_HASH").as_bytes(),
+    ];
+    for dep in deps {
+        parts.push(dep.name.as_bytes());
+        parts.push(dep.key.as_bytes());
+    }
+    let key = Cache::key(&parts);
+    if let Some((wasm, diagnostics)) = cache.as_ref().and_then(|cache| cache.get(&key)) {
+        return Ok(Build {
+            wasm: Some(wasm),
+            diagnostics,
+        });
+    }
+
+    let build = run_rustc(name, source, deps)?;
+    if let (Some(cache), Some(wasm)) = (&cache, &build.wasm) {
+        // Failing to cache the module shouldn't fail the build.
+        let _ = cache.insert(&key, wasm, &build.diagnostics);
+    }
+    Ok(build)
+}
+
+/// Write the runtime crates' rlibs into `dir`, returning the arguments which
+/// make them available to rustc.
+fn runtime_args(dir: &Path) -> io::Result<Vec<String>> {
+    let mut args = vec![
+        "-L".to_owned(),
+        format!("dependency={}", dir.to_str().unwrap()),
+    ];
+    for &(name, rlib) in RUNTIME_RLIBS {
+        let path = dir.join(format!("lib{}.rlib", name));
+        fs::write(&path, rlib)?;
+        args.push("--extern".to_owned());
+        args.push(format!("{}={}", name, path.to_str().unwrap()));
+    }
+    Ok(args)
+}
+
 /// Invoke rustc to build a `wasm32-unknown-unknown` crate with dependencies on
-/// `unicode_xid`, `proc_macro2`, `syn`, and `quote`.
-fn build_code(name: &str, source: &str) -> io::Result<Vec<u8>> {
+/// `unicode_xid`, `proc_macro2`, `syn`, and `quote`, and any additional
+/// dependencies declared by the crate.
+fn run_rustc(name: &str, source: &str, deps: &[Dependency]) -> io::Result<Build> {
     // Build within a tempdir
     let tmp = TempDir::new("ctrs_build")?;
     let wasm_path = tmp.path().join(format!("{}.wasm", name));
 
-    // Write out the rlibs
-    let unicode_xid_path = tmp.path().join("libunicode_xid.rlib");
-    let proc_macro2_path = tmp.path().join("libproc_macro2.rlib");
-    let syn_path = tmp.path().join("libsyn.rlib");
-    let quote_path = tmp.path().join("libquote.rlib");
-    fs::write(&unicode_xid_path, UNICODE_XID_RLIB)?;
-    fs::write(&proc_macro2_path, PROC_MACRO2_RLIB)?;
-    fs::write(&syn_path, SYN_RLIB)?;
-    fs::write(&quote_path, QUOTE_RLIB)?;
-
     // Run the compiler
-    let mut child = Command::new(env!("RUSTC"))
-        .stdin(Stdio::piped())
+    let mut cmd = Command::new(env!("RUSTC"));
+    cmd.stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .args(rustc_flags()?)
         .args(&[
-            "--target",
-            "wasm32-unknown-unknown",
-            "--edition",
-            "2018",
-            "--crate-type",
-            "cdylib",
+            "--error-format",
+            "json",
             "--crate-name",
             name,
             "-o",
             wasm_path.to_str().unwrap(),
-            "-L",
-            &format!("dependency={}", tmp.path().to_str().unwrap()),
-            "--extern",
-            &format!("unicode_xid={}", unicode_xid_path.to_str().unwrap()),
-            "--extern",
-            &format!("proc_macro2={}", proc_macro2_path.to_str().unwrap()),
-            "--extern",
-            &format!("syn={}", syn_path.to_str().unwrap()),
-            "--extern",
-            &format!("quote={}", quote_path.to_str().unwrap()),
-            "-",
         ])
-        .spawn()?;
+        .args(runtime_args(tmp.path())?);
+    for dep in deps {
+        cmd.arg("--extern")
+            .arg(format!("{}={}", dep.name, dep.rlib.display()))
+            .arg("-L")
+            .arg(format!("dependency={}", dep.rlib.parent().unwrap().display()));
+    }
+    let mut child = cmd.arg("-").spawn()?;
     child.stdin.take().unwrap().write_all(source.as_bytes())?;
 
-    let status = child.wait()?;
-    if !status.success() {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("rustc exited with status {}", status),
-        ));
+    let output = child.wait_with_output()?;
+    let diagnostics = String::from_utf8_lossy(&output.stderr).into_owned();
+    if !output.status.success() {
+        return Ok(Build {
+            wasm: None,
+            diagnostics,
+        });
     }
 
     // Read in the resulting wasm file
-    Ok(fs::read(&wasm_path)?)
+    Ok(Build {
+        wasm: Some(fs::read(&wasm_path)?),
+        diagnostics,
+    })
+}
+
+/// Macro crate modules loaded by this process, keyed by the hash of their
+/// contents.
+///
+/// Modules are leaked so that watt, which caches instantiated modules by
+/// their address, only instantiates each module once per process. The leak is
+/// deliberate: watt keeps every instance, whose memory is far larger than the
+/// module, for the life of the process anyway, and freeing a module would
+/// leave an instance in its cache keyed by an address which may be reused.
+/// Each distinct module is only leaked once, so long-lived processes such as
+/// IDE servers grow by one copy per version of each macro crate they build.
+static MODULES: Mutex<BTreeMap<String, &'static [u8]>> = Mutex::new(BTreeMap::new());
+
+/// Build a macro crate and its dependencies, producing the macros declared
+/// by it. Diagnostics within code loaded from `files` are reported within
+/// those files.
+fn build_wasm(mut iter: proc_macro::token_stream::IntoIter, files: Vec<SourceFile>) -> TokenStream {
+    let krate = iter.next().expect("missing crate name");
+    let decls = iter.next().expect("missing dependency list");
+    let body = iter.next().expect("missing crate body");
+
+    let (deps, _tmp) = match build_deps(decls) {
+        Ok(deps) => deps,
+        Err(errors) => return errors,
+    };
+    match build_crate(&krate, body, &deps, files, true) {
+        Ok((wasm, source, mut result)) => {
+            // Rebuild when the source of a dependency changes.
+            for dep in &deps {
+                for file in &dep.files {
+                    result.extend(track_file(file));
+                }
+            }
+
+            // The `ctrs` command-line tool collects built macro crates.
+            let macros = iter.collect::<TokenStream>();
+            if let Some(dir) = env::var_os("CTRS_EMIT_DIR") {
+                let krate_name = krate.to_string();
+                let hash = module_hash(&wasm);
+                if let Err(err) = manifest::write(Path::new(&dir), &krate_name, &wasm, &hash, macros.clone()) {
+                    let message = format!("error writing macro crate `{}`: {}", krate, err);
+                    result.extend(diagnostics::compile_error(krate.span(), krate.span(), &message));
+                }
+            }
+
+            // Macro crates with additional dependencies are only run as wasm,
+            // as their dependencies are only built for wasm.
+            match backend() {
+                Ok(Backend::Wasm) => {}
+                #[cfg(feature = "native")]
+                Ok(_) if deps.is_empty() => {
+                    if let Err(err) = native::build(&krate.to_string(), &source, &module_hash(&wasm)) {
+                        result.extend(diagnostics::compile_error(krate.span(), krate.span(), &err));
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => result.extend(diagnostics::compile_error(krate.span(), krate.span(), &err)),
+            }
+            #[cfg(not(feature = "native"))]
+            let _ = source;
+
+            result.extend(define_macros(krate, wasm, macros));
+            result
+        }
+        Err(errors) => errors,
+    }
+}
+
+/// How macros are run, as selected by `CTRS_BACKEND`.
+enum Backend {
+    /// Interpret the wasm module of the macro crate. This is the default.
+    Wasm,
+    /// Build the macro crate for the host, and load it as a dynamic library.
+    Native,
+    /// Run macros both natively and as wasm, reporting an error if their
+    /// outputs differ.
+    Differential,
+}
+
+fn backend() -> Result<Backend, String> {
+    let backend = match &env::var("CTRS_BACKEND").unwrap_or_default()[..] {
+        "" | "wasm" => return Ok(Backend::Wasm),
+        "native" => Backend::Native,
+        "differential" => Backend::Differential,
+        other => return Err(format!("unknown `CTRS_BACKEND` `{}`", other)),
+    };
+    if cfg!(feature = "native") {
+        Ok(backend)
+    } else {
+        Err("the native backend requires the `native` feature of `ctrs`".to_owned())
+    }
+}
+
+/// Define the macros of a built macro crate, which are described by `macros`
+/// as passed along with `__build_wasm__`.
+fn define_macros(krate: TokenTree, wasm: Vec<u8>, macros: TokenStream) -> TokenStream {
+    let wasm_lit = format!("\"{}\"", encode_module(&wasm))
+        .parse::<TokenStream>()
+        .unwrap();
+    let hash_lit = format!("\"{}\"", register_module(wasm))
+        .parse::<TokenStream>()
+        .unwrap();
+
+    // $krate "b64str" "hash" ...
+    let mut mac_args = TokenStream::new();
+    mac_args.extend(iter::once(krate));
+    mac_args.extend(wasm_lit);
+    mac_args.extend(hash_lit);
+    mac_args.extend(macros);
+
+    watt::proc_macro("build_result", mac_args, IMPL_WA)
+}
+
+/// Load the source of a macro crate from a file, relative to the invoking
+/// crate's manifest directory, and build it as if it had been written
+/// inline.
+fn load_source(mut iter: proc_macro::token_stream::IntoIter) -> TokenStream {
+    let krate = iter.next().expect("missing crate name");
+    let decls = match iter.next().expect("missing dependency list") {
+        TokenTree::Group(grp) => grp.stream(),
+        _ => panic!("expected dependency list"),
+    };
+    let path_lit = iter.next().expect("missing source path");
+
+    let path = path_lit.to_string();
+    assert!(path.starts_with('"') && path.ends_with('"'));
+    let path = &path[1..path.len() - 1];
+    let full_path = source_dir().join(path);
+    let text = match fs::read_to_string(&full_path) {
+        Ok(text) => text,
+        Err(err) => {
+            let message = format!("couldn't read `{}`: {}", full_path.display(), err);
+            return diagnostics::compile_error(path_lit.span(), path_lit.span(), &message);
+        }
+    };
+    let stream = match text.parse::<TokenStream>() {
+        Ok(stream) => stream,
+        Err(err) => {
+            let message = format!("couldn't parse `{}`: {:?}", path, err);
+            return diagnostics::compile_error(path_lit.span(), path_lit.span(), &message);
+        }
+    };
+
+    // Run the file through the same processing as an inline macro crate:
+    // macro crate $krate(..deps..); ..src..
+    let mut input = TokenStream::new();
+    input.extend("macro crate".parse::<TokenStream>().unwrap());
+    input.extend(iter::once(krate));
+    input.extend(iter::once(TokenTree::Group(Group::new(Delimiter::Parenthesis, decls))));
+    input.extend(";".parse::<TokenStream>().unwrap());
+    input.extend(stream.clone());
+    let output = watt::proc_macro("ctrs", input, IMPL_WA);
+
+    let root_dir = full_path.parent().unwrap();
+    let file = SourceFile::new(None, path.to_owned(), text, path_lit.span(), stream);
+    let mut result = expand_crate(output, root_dir, vec![file]);
+    result.extend(track_file(&full_path));
+    result
+}
+
+/// Handle the output of processing a macro crate in `IMPL_WA`, which is a
+/// `ctrs!` invocation to either build the crate, or to load its out-of-line
+/// modules, from files relative to `root_dir`, and then process it again.
+///
+/// The output is passed along as is if it is anything else, such as errors.
+fn expand_crate(mut output: TokenStream, root_dir: &Path, mut files: Vec<SourceFile>) -> TokenStream {
+    let mut tracked = TokenStream::new();
+    loop {
+        let args = match output.clone().into_iter().last() {
+            Some(TokenTree::Group(grp)) if grp.delimiter() == Delimiter::Brace => grp.stream(),
+            _ => return output,
+        };
+        let mut iter = args.into_iter();
+        let message = iter.next().map(|id| id.to_string()).unwrap_or_default();
+        match &message[..] {
+            "__build_wasm__" => {
+                let mut result = build_wasm(iter, files);
+                result.extend(tracked);
+                return result;
+            }
+            "__load_modules__" => match load_modules(iter, root_dir, &mut files, &mut tracked) {
+                Ok(input) => output = watt::proc_macro("ctrs", input, IMPL_WA),
+                Err(errors) => return errors,
+            },
+            _ => return output,
+        }
+    }
+}
+
+/// Read the files of the out-of-line modules requested by `IMPL_WA`, which
+/// are passed as `[$name "key", ..] ["key" { .. }, ..] ..input..`, and add
+/// them to the contents of the modules which have already been read.
+///
+/// Like rustc, the file for the key `a/b` is either `a/b.rs` or `a/b/mod.rs`.
+fn load_modules(
+    mut iter: proc_macro::token_stream::IntoIter,
+    root_dir: &Path,
+    files: &mut Vec<SourceFile>,
+    tracked: &mut TokenStream,
+) -> Result<TokenStream, TokenStream> {
+    let requests = match iter.next().expect("missing module requests") {
+        TokenTree::Group(grp) => grp.stream(),
+        _ => panic!("expected module requests"),
+    };
+    let mut modules = match iter.next().expect("missing module contents") {
+        TokenTree::Group(grp) => grp.stream(),
+        _ => panic!("expected module contents"),
+    };
+
+    let source_dir = source_dir();
+    let mut requests = requests.into_iter();
+    while let Some(name) = requests.next() {
+        let key_lit = requests.next().expect("missing module key");
+        requests.next(); // `,`
+
+        let key = key_lit.to_string();
+        let key = &key[1..key.len() - 1];
+        let candidates = [
+            root_dir.join(format!("{}.rs", key)),
+            root_dir.join(key).join("mod.rs"),
+        ];
+        let path = match candidates.iter().find(|path| path.is_file()) {
+            Some(path) => path,
+            None => {
+                let message = format!(
+                    "file not found for module `{}`\n= help: create `{}` or `{}`",
+                    name,
+                    candidates[0].display(),
+                    candidates[1].display(),
+                );
+                return Err(diagnostics::compile_error(name.span(), name.span(), &message));
+            }
+        };
+
+        let text = fs::read_to_string(path).map_err(|err| {
+            let message = format!("couldn't read `{}`: {}", path.display(), err);
+            diagnostics::compile_error(name.span(), name.span(), &message)
+        })?;
+        let stream = text.parse::<TokenStream>().map_err(|err| {
+            let message = format!("couldn't parse `{}`: {:?}", path.display(), err);
+            diagnostics::compile_error(name.span(), name.span(), &message)
+        })?;
+
+        // "key" { ..contents.. },
+        modules.extend(iter::once(key_lit));
+        modules.extend(iter::once(TokenTree::Group(Group::new(Delimiter::Brace, stream.clone()))));
+        modules.extend(",".parse::<TokenStream>().unwrap());
+
+        let display = path.strip_prefix(&source_dir).unwrap_or(path);
+        let display = display.to_string_lossy().into_owned();
+        files.push(SourceFile::new(Some(key.to_owned()), display, text, name.span(), stream));
+        tracked.extend(track_file(path));
+    }
+
+    let mut input = TokenStream::new();
+    input.extend(iter::once(TokenTree::Group(Group::new(Delimiter::Bracket, modules))));
+    input.extend(iter);
+    Ok(input)
+}
+
+/// Build a macro crate from the body of a `ctrs!` invocation.
+///
+/// Diagnostics from rustc are reported at the tokens which caused them, and
+/// are returned along with the module and the source it was built from if
+/// the build succeeded.
+fn build_crate(
+    krate: &TokenTree,
+    body: TokenTree,
+    deps: &[Dependency],
+    files: Vec<SourceFile>,
+    warnings: bool,
+) -> Result<(Vec<u8>, String, TokenStream), TokenStream> {
+    let source = match body {
+        TokenTree::Group(grp) => SourceMap::with_files(grp.stream(), files),
+        _ => panic!("expected crate body block"),
+    };
+
+    let build = match build_code(&krate.to_string(), &source.source, deps) {
+        Ok(build) => build,
+        Err(err) => {
+            let message = format!("error building macro crate `{}`: {}", krate, err);
+            return Err(diagnostics::compile_error(krate.span(), krate.span(), &message));
+        }
+    };
+
+    let mut result = TokenStream::new();
+    let mut has_errors = false;
+    for line in build.diagnostics.lines() {
+        if let Ok(diagnostic) = serde_json::from_str::<serde_json::Value>(line) {
+            if diagnostic["level"] == "warning" && !warnings {
+                continue;
+            }
+            has_errors |= diagnostic["level"] == "error";
+            result.extend(source.lower(&diagnostic));
+        }
+    }
+
+    match build.wasm {
+        Some(wasm) => Ok((wasm, source.source, result)),
+        None => {
+            let link_error = Limits::from_env()
+                .ok()
+                .and_then(|limits| limits.link_error(&krate.to_string(), &build.diagnostics));
+            if let Some(message) = link_error {
+                result = diagnostics::compile_error(krate.span(), krate.span(), &message);
+            } else if !has_errors {
+                let message = format!("error building macro crate `{}`", krate);
+                result.extend(diagnostics::compile_error(krate.span(), krate.span(), &message));
+            }
+            Err(result)
+        }
+    }
+}
+
+/// Build the additional dependencies declared in a `macro crate` header,
+/// which are passed as `[name = "path", ...]`.
+///
+/// If caching is disabled, dependencies are built in a temporary directory
+/// which must be kept alive until the macro crate has been built.
+fn build_deps(decls: TokenTree) -> Result<(Vec<Dependency>, Option<TempDir>), TokenStream> {
+    let decls = match decls {
+        TokenTree::Group(grp) => grp.stream(),
+        _ => panic!("expected dependency list"),
+    };
+
+    let mut deps = Vec::new();
+    let mut iter = decls.into_iter().peekable();
+    if iter.peek().is_none() {
+        return Ok((deps, None));
+    }
+
+    let (out_dir, tmp) = match Cache::open() {
+        Some(cache) => (cache.deps_dir(), None),
+        None => match TempDir::new("ctrs_deps") {
+            Ok(tmp) => (tmp.path().to_owned(), Some(tmp)),
+            Err(err) => {
+                let message = format!("error building dependencies: {}", err);
+                return Err(diagnostics::compile_error(Span::call_site(), Span::call_site(), &message));
+            }
+        },
+    };
+
+    while let Some(name) = iter.next() {
+        iter.next().expect("missing `=`");
+        let path = iter.next().expect("missing dependency path").to_string();
+        iter.next(); // `,`
+
+        assert!(path.starts_with('"') && path.ends_with('"'));
+        match deps::build(&name.to_string(), &path[1..path.len() - 1], &deps, &out_dir) {
+            Ok(dep) => deps.push(dep),
+            Err(message) => return Err(diagnostics::compile_error(name.span(), name.span(), &message)),
+        }
+    }
+    Ok((deps, tmp))
+}
+
+/// The directory which paths in macro crates are relative to. This is the
+/// invoking crate's manifest directory, unless overridden with
+/// `CTRS_SOURCE_DIR` by the `ctrs` command-line tool.
+fn source_dir() -> PathBuf {
+    env::var_os("CTRS_SOURCE_DIR")
+        .or_else(|| env::var_os("CARGO_MANIFEST_DIR"))
+        .map(PathBuf::from)
+        .unwrap_or_default()
+}
+
+/// Build an item which makes cargo rebuild the invoking crate when the file at
+/// `path` changes.
+fn track_file(path: &Path) -> TokenStream {
+    let path = Literal::string(path.to_str().expect("non-utf8 path"));
+    format!("const _: &[u8] = include_bytes!({});", path)
+        .parse()
+        .unwrap()
+}
+
+/// Compress and base64-encode a macro crate module. The codec used is
+/// recorded as a prefix of the result, which is understood by
+/// `decode_module`.
+fn encode_module(wasm: &[u8]) -> String {
+    let compressed = miniz_oxide::deflate::compress_to_vec(wasm, 9);
+    format!("deflate:{}", base64::encode(&compressed))
+}
+
+/// Decode a module encoded by `encode_module`. Modules without a codec
+/// prefix are uncompressed.
+fn decode_module(encoded: &str) -> Result<Vec<u8>, String> {
+    let mut parts = encoded.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(data), None) => base64::decode(data).map_err(|err| err.to_string()),
+        (Some("deflate"), Some(data)) => {
+            let compressed = base64::decode(data).map_err(|err| err.to_string())?;
+            miniz_oxide::inflate::decompress_to_vec(&compressed)
+                .map_err(|err| format!("{:?}", err))
+        }
+        (Some(codec), Some(_)) => Err(format!("unknown codec `{}`", codec)),
+        (None, _) => unreachable!(),
+    }
+}
+
+/// The hash which identifies a macro crate module.
+fn module_hash(wasm: &[u8]) -> String {
+    Sha256::digest(wasm)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Record a macro crate module as loaded, returning its hash. The first time
+/// a process loads a module, it is also kept in the cache, so that other
+/// processes can run its macros.
+fn register_module(wasm: Vec<u8>) -> String {
+    let hash = module_hash(&wasm);
+    let mut modules = MODULES.lock().unwrap();
+    if !modules.contains_key(&hash) {
+        if let Some(cache) = Cache::open() {
+            let _ = cache.insert_module(&hash, &wasm);
+        }
+        modules.insert(hash.clone(), Box::leak(wasm.into_boxed_slice()));
+    }
+    hash
+}
+
+/// Find a loaded module by hash, loading it from the cache if it is kept
+/// there.
+fn find_module(hash: &str) -> Option<&'static [u8]> {
+    let loaded = MODULES.lock().unwrap().get(hash).copied();
+    if loaded.is_some() {
+        return loaded;
+    }
+    let wasm = Cache::open()?.module(hash)?;
+    if module_hash(&wasm) != hash {
+        return None;
+    }
+    register_module(wasm);
+    MODULES.lock().unwrap().get(hash).copied()
+}
+
+/// Run a macro from a loaded macro crate module.
+///
+/// Invocations refer to the module by hash. If this process has not yet
+/// loaded the module, and it isn't in the cache, the invocation is forwarded
+/// to the `$module` macro generated by `build_result`, which holds the only
+/// copy of the encoded module and passes it back to us with `__load_wasm__`.
+fn eval_wasm(mut iter: proc_macro::token_stream::IntoIter, allow_load: bool) -> TokenStream {
+    let module = match iter.next().expect("missing module macro") {
+        TokenTree::Group(grp) => grp,
+        _ => panic!("expected module macro path"),
+    };
+    let krate = iter.next().expect("missing crate name");
+    let name = iter.next().expect("missing macro name");
+    let func = iter.next().expect("missing func name");
+    let hash_lit = iter.next().expect("missing module hash");
+
+    let hash = hash_lit.to_string();
+    assert!(hash.starts_with('"') && hash.ends_with('"'));
+    let wasm = match find_module(&hash[1..hash.len() - 1]) {
+        Some(wasm) => wasm,
+        None if allow_load => {
+            // $module! { __eval_wasm__ [$module] $krate $mname $func "hash" ... }
+            let mut args = TokenStream::new();
+            args.extend("__eval_wasm__".parse::<TokenStream>().unwrap());
+            args.extend(vec![
+                TokenTree::Group(module.clone()),
+                krate,
+                name,
+                func,
+                hash_lit,
+            ]);
+            args.extend(iter);
+
+            let mut result = module.stream();
+            result.extend("!".parse::<TokenStream>().unwrap());
+            result.extend(iter::once(TokenTree::Group(Group::new(Delimiter::Brace, args))));
+            return result;
+        }
+        None => {
+            let message = format!("module for macro crate `{}` does not match its hash", krate);
+            return diagnostics::compile_error(Span::call_site(), Span::call_site(), &message);
+        }
+    };
+
+    let krate = krate.to_string();
+    let name = name.to_string();
+    let func = func.to_string();
+    run_macro(&krate, &name, &func, iter.collect(), &hash[1..hash.len() - 1], wasm)
+}
+
+/// Patterns of the paths which the macros of a loaded module may read, which
+/// its macro crate declared with `#![ctrs_host(read_files(..))]`, keyed by
+/// the hash of the module.
+static READ_FILES: Mutex<BTreeMap<String, Vec<String>>> = Mutex::new(BTreeMap::new());
+
+/// The patterns of the paths which the macros of the module `wasm` may read,
+/// as returned by its `__ctrs_host_read_files` export, if it has one.
+fn read_file_patterns(hash: &str, wasm: &'static [u8]) -> Vec<String> {
+    let mut patterns = READ_FILES.lock().unwrap_or_else(|err| err.into_inner());
+    let patterns = patterns.entry(hash.to_owned()).or_insert_with(|| {
+        let declared = manifest::exported_functions(wasm)
+            .is_ok_and(|exports| exports.iter().any(|export| export == "__ctrs_host_read_files"));
+        if !declared {
+            return Vec::new();
+        }
+        watt::proc_macro("__ctrs_host_read_files", TokenStream::new(), wasm)
+            .into_iter()
+            .filter_map(|token| host::string_value(&token.to_string()))
+            .collect()
+    });
+    patterns.clone()
+}
+
+/// Call the export `func` of a loaded macro crate module, or of its native
+/// build if `CTRS_BACKEND` selects one and it has been built.
+fn run_macro(
+    krate: &str,
+    name: &str,
+    func: &str,
+    input: TokenStream,
+    hash: &str,
+    wasm: &'static [u8],
+) -> TokenStream {
+    // Only exports generated by `ctrs` read the data passed by the host, and
+    // files are only passed to crates which declare the paths they read.
+    let input = if func.starts_with("__ctrs_export_") {
+        host::provide(input, &read_file_patterns(hash, wasm))
+    } else {
+        input
+    };
+
+    let limits = match Limits::from_env() {
+        Ok(limits) => limits,
+        Err(message) => return diagnostics::compile_error(Span::call_site(), Span::call_site(), &message),
+    };
+
+    // Report panics, exceeded limits and oversized output as errors at the
+    // macro invocation.
+    let finish = |output: Result<TokenStream, String>| {
+        let output = output
+            .and_then(|output| host::finish(krate, name, output))
+            .and_then(|output| limits.check_output(krate, name, &output).map(|()| output));
+        output.unwrap_or_else(|message| diagnostics::compile_error(Span::call_site(), Span::call_site(), &message))
+    };
+
+    #[cfg(feature = "native")]
+    {
+        let backend = backend().unwrap_or(Backend::Wasm);
+        if !matches!(backend, Backend::Wasm) {
+            if let Some(native) = native::run(hash, func, input.clone()) {
+                let native = native.map_err(|reason| {
+                    format!("proc macro `{}` from macro crate `{}` panicked: {}", name, krate, reason)
+                });
+                if matches!(backend, Backend::Native) {
+                    return finish(native);
+                }
+
+                let wasm = run_wasm(&limits, krate, name, func, input, wasm);
+                let describe = |output: &Result<TokenStream, String>| match output {
+                    Ok(output) => output.to_string(),
+                    Err(message) => message.clone(),
+                };
+                // Panic messages may differ, as traps are only caught in wasm.
+                let same = match (&native, &wasm) {
+                    (Ok(native), Ok(wasm)) => native.to_string() == wasm.to_string(),
+                    (Err(_), Err(_)) => true,
+                    _ => false,
+                };
+                if !same {
+                    let message = format!(
+                        "proc macro `{}` from macro crate `{}` produced different output natively and as wasm\n\
+                         native: {}\n\
+                         wasm: {}",
+                        name,
+                        krate,
+                        describe(&native),
+                        describe(&wasm)
+                    );
+                    return diagnostics::compile_error(Span::call_site(), Span::call_site(), &message);
+                }
+                return finish(wasm);
+            }
+        }
+    }
+    #[cfg(not(feature = "native"))]
+    let _ = hash;
+
+    finish(run_wasm(&limits, krate, name, func, input, wasm))
+}
+
+/// Call the export `func` of a wasm module, which runs the macro `name` from
+/// the macro crate `krate`. Traps and panics within the module are returned
+/// as error messages, to be reported at the macro invocation, rather than
+/// panicking ourselves.
+///
+/// What the module prints to stderr is passed through if it runs to
+/// completion. A panic is described by the message printed by the module.
+/// Traps without one are described along with the limit they likely
+/// exceeded, if any, or else by the interpreter's description of the trap.
+fn run_wasm(
+    limits: &Limits,
+    krate: &str,
+    name: &str,
+    func: &str,
+    input: TokenStream,
+    wasm: &'static [u8],
+) -> Result<TokenStream, String> {
+    let (result, printed) =
+        stderr::capture(|| panic::catch_unwind(AssertUnwindSafe(|| watt::proc_macro(func, input, wasm))));
+    let result = result.inspect(|_| eprint!("{}", printed));
+    result.map_err(|payload| {
+        let panicked = |reason: &str| {
+            format!("proc macro `{}` from macro crate `{}` panicked: {}", name, krate, reason)
+        };
+        if !printed.trim().is_empty() {
+            return panicked(printed.trim());
+        }
+        let trap = payload
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_else(|| "unknown error".to_owned());
+        limits
+            .trap_error(krate, name, &trap)
+            .unwrap_or_else(|| panicked(&trap))
+    })
 }
 
 fn log_stream(ts: &TokenStream) -> String {
@@ -112,41 +830,56 @@ pub fn ctrs(ts: TokenStream) -> TokenStream {
     let mut iter = ts.clone().into_iter();
     let first_id = iter.next().map(|id| id.to_string()).unwrap_or_default();
     let os = match &first_id[..] {
-        // ctrs!(__build_wasm__ $krate {..src..} ...) => build_result($krate "b64" ...)
-        "__build_wasm__" => {
-            let krate = iter.next().expect("missing crate name");
-            let source = match iter.next().expect("missing crate body") {
-                TokenTree::Group(grp) => grp.stream().to_string(),
-                _ => panic!("expected crate body block"),
-            };
+        // ctrs!(__build_wasm__ $krate [..deps..] {..src..} ...) => build_result($krate "b64" "hash" ...)
+        "__build_wasm__" => build_wasm(iter, Vec::new()),
 
-            let wasm = build_code(&krate.to_string(), &source).expect("error building crate");
-            let wasm_lit = format!("\"{}\"", base64::encode(&wasm))
-                .parse::<TokenStream>()
-                .unwrap();
+        // ctrs!(__load_source__ $krate [..deps..] "path") => build_wasm of the file's contents
+        "__load_source__" => load_source(iter),
 
-            // $krate "b64str" ...
-            let mut mac_args = TokenStream::new();
-            mac_args.extend(iter::once(krate));
-            mac_args.extend(wasm_lit);
-            mac_args.extend(iter);
+        // ctrs!(__build_eval__ $krate {..src..}) => result of calling `__ctrs_eval`
+        "__build_eval__" => {
+            let krate = iter.next().expect("missing crate name");
+            let body = iter.next().expect("missing crate body");
 
-            watt::proc_macro("build_result", mac_args, IMPL_WA)
+            // Warnings are lowered to items, which may not be valid where
+            // `eval!` was invoked, so only errors are reported.
+            match build_crate(&krate, body, &[], Vec::new(), false) {
+                Ok((wasm, _, mut result)) => {
+                    let hash = register_module(wasm);
+                    let wasm = MODULES.lock().unwrap()[&hash];
+                    let krate = krate.to_string();
+                    result.extend(run_macro(&krate, "eval", "__ctrs_eval", TokenStream::new(), &hash, wasm));
+                    result
+                }
+                // `eval!` is an expression, so several errors must be in a
+                // block.
+                Err(errors) => TokenTree::Group(Group::new(Delimiter::Brace, errors)).into(),
+            }
         }
 
-        // ctrs!(__eval_wasm__ $mname "b64str" ...) => result of calling method
-        "__eval_wasm__" => {
-            let func = iter.next().expect("missing func name").to_string();
-            let wasm_lit = iter.next().expect("missing wasm src").to_string();
+        // ctrs!(__eval_wasm__ [$module] $krate $mname $func "hash" ...) => result of calling method
+        "__eval_wasm__" => eval_wasm(iter, true),
 
+        // ctrs!(__load_wasm__ "b64str" __eval_wasm__ ...) => result of calling method
+        "__load_wasm__" => {
+            let wasm_lit = iter.next().expect("missing wasm src").to_string();
             assert!(wasm_lit.starts_with('"') && wasm_lit.ends_with('"'));
-            let wasm = base64::decode(&wasm_lit[1..wasm_lit.len() - 2]).unwrap();
+            let wasm = decode_module(&wasm_lit[1..wasm_lit.len() - 1])
+                .expect("invalid wasm module encoding");
+            register_module(wasm);
 
-            watt::proc_macro(&func, iter.collect(), &wasm)
+            assert_eq!(iter.next().map(|id| id.to_string()), Some("__eval_wasm__".to_owned()));
+            eval_wasm(iter, false)
         }
 
+        // ctrs!(__strip_helpers__ [helpers] [derives] ...) => next derive, or item with helpers removed
+        "__strip_helpers__" => watt::proc_macro("strip_helpers", iter.collect(), IMPL_WA),
+
         // Not an internal method! Hand over.
-        _ => watt::proc_macro("ctrs", ts, IMPL_WA),
+        _ => {
+            let output = watt::proc_macro("ctrs", ts, IMPL_WA);
+            expand_crate(output, &source_dir(), Vec::new())
+        }
     };
 
     if ctrs_log {
@@ -154,3 +887,127 @@ pub fn ctrs(ts: TokenStream) -> TokenStream {
     }
     os
 }
+
+/// Import the macros from a precompiled macro crate, such as one built by the
+/// `ctrs` command-line tool. The path is relative to the invoking crate's
+/// manifest directory.
+///
+/// The module isn't rebuilt, but `ctrs` itself is still built with its
+/// runtime for `wasm32-unknown-unknown`, so the target must be installed.
+///
+/// ```ignore
+/// ctrs::load_wasm!("precompiled/my_macros.wasm");
+/// ```
+#[proc_macro]
+pub fn load_wasm(ts: TokenStream) -> TokenStream {
+    let path_lit = match ts.into_iter().next() {
+        Some(TokenTree::Literal(lit)) => lit,
+        _ => {
+            let message = "expected the path of a `.wasm` file";
+            return diagnostics::compile_error(Span::call_site(), Span::call_site(), message);
+        }
+    };
+    let error = |message: String| diagnostics::compile_error(path_lit.span(), path_lit.span(), &message);
+
+    let path = path_lit.to_string();
+    if !path.starts_with('"') || !path.ends_with('"') {
+        return error("expected the path of a `.wasm` file".to_owned());
+    }
+    let full_path = source_dir().join(&path[1..path.len() - 1]);
+    let wasm = match fs::read(&full_path) {
+        Ok(wasm) => wasm,
+        Err(err) => return error(format!("couldn't read `{}`: {}", full_path.display(), err)),
+    };
+    let precompiled = match manifest::read(&full_path, &wasm) {
+        Ok(precompiled) => precompiled,
+        Err(err) => return error(format!("couldn't load `{}`: {}", full_path.display(), err)),
+    };
+    if precompiled.hash.is_some_and(|hash| hash != module_hash(&wasm)) {
+        return error(format!("`{}` doesn't match its manifest", full_path.display()));
+    }
+
+    let mut result = track_file(&full_path);
+    if let Some(manifest) = &precompiled.manifest {
+        result.extend(track_file(manifest));
+    }
+    let krate = TokenTree::Ident(Ident::new(&precompiled.krate, path_lit.span()));
+    result.extend(define_macros(krate, wasm, precompiled.macros));
+    result
+}
+
+/// Pack the arguments and input of an attribute macro into a single stream of
+/// the form `(args) input`.
+fn pack_attr(args: TokenStream, input: TokenStream) -> TokenStream {
+    let mut packed = TokenStream::new();
+    packed.extend(iter::once(TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        args,
+    ))));
+    packed.extend(input);
+    packed
+}
+
+/// Apply derive macros defined in a macro crate with `#[proc_macro_derive]`.
+///
+/// ```ignore
+/// #[ctrs::derive(MyDerive)]
+/// struct Foo {
+///     #[my_helper]
+///     x: u32,
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn derive(args: TokenStream, input: TokenStream) -> TokenStream {
+    watt::proc_macro("derive", pack_attr(args, input), IMPL_WA)
+}
+
+/// Evaluate a block at compile time, expanding to the tokens of its result.
+///
+/// The block is built like the body of a macro crate, and may use
+/// `proc_macro2`, `syn` and `quote`. Its value must implement
+/// `quote::ToTokens`.
+///
+/// ```ignore
+/// const SQUARES: [u32; 16] = ctrs::eval! {
+///     let squares = (0..16u32).map(|n| n * n);
+///     quote::quote!([#(#squares),*])
+/// };
+/// ```
+#[proc_macro]
+pub fn eval(ts: TokenStream) -> TokenStream {
+    watt::proc_macro("eval", ts, IMPL_WA)
+}
+
+/// Apply attribute macros defined in a macro crate with
+/// `#[proc_macro_attribute]`.
+///
+/// ```ignore
+/// #[ctrs::attr(my_attr(some args))]
+/// fn foo() {}
+/// ```
+#[proc_macro_attribute]
+pub fn attr(args: TokenStream, input: TokenStream) -> TokenStream {
+    watt::proc_macro("attr", pack_attr(args, input), IMPL_WA)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_encoding() {
+        let wasm = b"\0asm\x01\0\0\0".repeat(100);
+        let encoded = encode_module(&wasm);
+        assert!(encoded.starts_with("deflate:"));
+        assert_eq!(decode_module(&encoded).unwrap(), wasm);
+    }
+
+    #[test]
+    fn decode_legacy_module() {
+        // Modules embedded before compression was added have no prefix.
+        let wasm = b"\0asm\x01\0\0\0".to_vec();
+        assert_eq!(decode_module(&base64::encode(&wasm)).unwrap(), wasm);
+        assert!(decode_module("zstd:AAAA").unwrap_err().contains("unknown codec"));
+        assert!(decode_module("not base64!").is_err());
+    }
+}
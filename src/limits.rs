@@ -0,0 +1,141 @@
+//! Limits on the resources used by macros.
+//!
+//! Memory and stack are bounded when a macro crate is linked, so they are
+//! part of the flags hashed into its cache key. Output is checked by the host
+//! after each invocation.
+//!
+//! Running time and call depth aren't limited, as watt has no way to meter
+//! the instructions executed by a module or to bound its call stack.
+
+use proc_macro::{TokenStream, TokenTree};
+use std::env;
+
+/// Size of a wasm memory page, which the memory limit is rounded up to.
+const PAGE_SIZE: u64 = 64 * 1024;
+
+/// Default cap on the linear memory of a macro crate, in bytes.
+const DEFAULT_MEMORY: u64 = 1024 * 1024 * 1024;
+
+/// Default size of the stack of a macro crate, in bytes.
+const DEFAULT_STACK: u64 = 1024 * 1024;
+
+/// Alignment required of the stack size, which it is rounded up to.
+const STACK_ALIGN: u64 = 16;
+
+/// Default cap on the number of tokens produced by one macro invocation.
+const DEFAULT_OUTPUT_TOKENS: usize = 10_000_000;
+
+pub struct Limits {
+    /// Maximum linear memory of a macro crate, in bytes.
+    pub memory: u64,
+    /// Stack size of a macro crate, in bytes.
+    pub stack: u64,
+    /// Maximum number of tokens, including groups, produced by a macro.
+    pub output_tokens: usize,
+}
+
+impl Limits {
+    /// Read the limits from `CTRS_MAX_MEMORY`, `CTRS_MAX_STACK` and
+    /// `CTRS_MAX_OUTPUT_TOKENS`, using the defaults for any which are unset. Returns an error message if any is invalid.
+    pub fn from_env() -> Result<Limits, String> {
+        fn var<T: std::str::FromStr>(name: &str, default: T, unit: &str) -> Result<T, String> {
+            match env::var(name) {
+                Ok(value) => value.trim().parse().map_err(|_| {
+                    format!("invalid value `{}` for `{}`: expected a number of {}", value, name, unit)
+                }),
+                Err(_) => Ok(default),
+            }
+        }
+        let limits = Limits {
+            memory: var("CTRS_MAX_MEMORY", DEFAULT_MEMORY, "bytes")?,
+            stack: var("CTRS_MAX_STACK", DEFAULT_STACK, "bytes")?,
+            output_tokens: var("CTRS_MAX_OUTPUT_TOKENS", DEFAULT_OUTPUT_TOKENS, "tokens")?,
+        };
+        if limits.stack == 0 {
+            return Err("`CTRS_MAX_STACK` must not be zero".to_owned());
+        }
+        if limits.memory <= limits.stack {
+            return Err(format!(
+                "`CTRS_MAX_MEMORY={}` must be larger than `CTRS_MAX_STACK={}`, as the stack is \
+                 part of a macro crate's memory",
+                limits.memory, limits.stack
+            ));
+        }
+        Ok(limits)
+    }
+
+    /// Flags passed to rustc to bound the memory and stack of the module.
+    pub fn rustc_flags(&self) -> Vec<String> {
+        let memory = self.memory.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let stack = self.stack.div_ceil(STACK_ALIGN) * STACK_ALIGN;
+        vec![
+            "-C".to_owned(),
+            format!("link-arg=--max-memory={}", memory),
+            "-C".to_owned(),
+            format!("link-arg=-zstack-size={}", stack),
+        ]
+    }
+
+    /// Describe a failure to link the macro crate `krate` because its stack
+    /// and static data don't fit in its memory limit, if `diagnostics` from
+    /// rustc report one.
+    pub fn link_error(&self, krate: &str, diagnostics: &str) -> Option<String> {
+        let needed = diagnostics.find("maximum memory too small")?;
+        let needed = diagnostics[needed..]
+            .split(|c: char| !c.is_ascii_digit())
+            .find(|number| !number.is_empty())
+            .map_or_else(String::new, |number| format!(", which needs {} bytes", number));
+        Some(format!(
+            "macro crate `{}` doesn't fit in `CTRS_MAX_MEMORY={}` bytes with a stack of \
+             `CTRS_MAX_STACK={}` bytes{}",
+            krate, self.memory, self.stack, needed
+        ))
+    }
+
+    /// Describe a trap of the macro `name` from the macro crate `krate` which
+    /// was likely caused by exceeding a limit, if it looks like one was.
+    ///
+    /// The stack is placed at the start of memory, so overflowing it accesses
+    /// memory out of bounds. Running out of memory aborts, which traps as
+    /// unreachable code without a panic message being printed first. Either
+    /// trap may have other causes, so the limit is only given as the likely
+    /// one, along with the trap.
+    pub fn trap_error(&self, krate: &str, name: &str, trap: &str) -> Option<String> {
+        let normalized = trap.to_lowercase().replace(['_', ' '], "");
+        let (limit, value) = if normalized.contains("outofbounds") {
+            ("CTRS_MAX_STACK", self.stack)
+        } else if normalized.contains("unreachable") {
+            ("CTRS_MAX_MEMORY", self.memory)
+        } else {
+            return None;
+        };
+        Some(format!(
+            "proc macro `{}` from macro crate `{}` trapped ({}), likely because it exceeded \
+             `{}={}` bytes",
+            name, krate, trap, limit, value
+        ))
+    }
+
+    /// Check the output of the macro `name` from the macro crate `krate`,
+    /// returning an error message if it is too large.
+    pub fn check_output(&self, krate: &str, name: &str, output: &TokenStream) -> Result<(), String> {
+        if count_tokens(output.clone()) <= self.output_tokens {
+            return Ok(());
+        }
+        Err(format!(
+            "proc macro `{}` from macro crate `{}` exceeded the output limit of {} tokens \
+             (set by `CTRS_MAX_OUTPUT_TOKENS`)",
+            name, krate, self.output_tokens
+        ))
+    }
+}
+
+fn count_tokens(stream: TokenStream) -> usize {
+    stream
+        .into_iter()
+        .map(|token| match token {
+            TokenTree::Group(group) => 1 + count_tokens(group.stream()),
+            _ => 1,
+        })
+        .sum()
+}
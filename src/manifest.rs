@@ -0,0 +1,332 @@
+//! Precompiled macro crates, written by the `ctrs` command-line tool and read
+//! by `ctrs::load_wasm!`.
+//!
+//! A precompiled crate is stored as `$NAME.wasm`, holding the module, along
+//! with a manifest `$NAME.json` describing the macros it exports:
+//!
+//! ```json
+//! {
+//!   "crate": "my_macros",
+//!   "hash": "<sha256 of the module>",
+//!   "macros": [
+//!     { "kind": "bang", "macro_export": true, "vis": "pub", "name": "my_macro", "export": "__ctrs_export_my_macro" },
+//!     { "kind": "derive", "vis": "", "name": "MyDerive", "export": "..", "helpers": ["my_attr"] }
+//!   ]
+//! }
+//! ```
+
+use proc_macro::{TokenStream, TokenTree};
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The macros exported by a precompiled macro crate.
+pub struct Precompiled {
+    /// Name of the macro crate.
+    pub krate: String,
+    /// Hash of the module recorded in the manifest, if any.
+    pub hash: Option<String>,
+    /// Macro definitions, as passed along with `__build_wasm__`.
+    pub macros: TokenStream,
+    /// Path of the manifest, if the module has one.
+    pub manifest: Option<PathBuf>,
+}
+
+/// Write a built macro crate and its manifest into `dir`. `macros` are the
+/// macro definitions passed along with `__build_wasm__`.
+pub fn write(dir: &Path, krate: &str, wasm: &[u8], hash: &str, macros: TokenStream) -> io::Result<()> {
+    let manifest = json!({
+        "crate": krate,
+        "hash": hash,
+        "macros": macros_to_json(macros),
+    });
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join(format!("{}.wasm", krate)), wasm)?;
+    fs::write(
+        dir.join(format!("{}.json", krate)),
+        serde_json::to_string_pretty(&manifest)?,
+    )
+}
+
+/// Convert macro definitions, such as `bang #[macro_export] pub(crate) name =
+/// func` or `derive Name = func [helper, ..]`, to their representation in a
+/// manifest.
+fn macros_to_json(macros: TokenStream) -> Vec<Value> {
+    let mut defs = vec![Vec::new()];
+    for token in macros {
+        match &token {
+            TokenTree::Punct(punct) if punct.as_char() == ',' => defs.push(Vec::new()),
+            _ => defs.last_mut().unwrap().push(token),
+        }
+    }
+
+    defs.into_iter()
+        .filter(|def| !def.is_empty())
+        .map(|def| {
+            let eq = def
+                .iter()
+                .position(|token| matches!(token, TokenTree::Punct(punct) if punct.as_char() == '='))
+                .expect("missing `=` in macro definition");
+            let macro_export = matches!(&def[1], TokenTree::Punct(punct) if punct.as_char() == '#');
+            let vis_start = if macro_export { 3 } else { 1 };
+            let vis = def[vis_start..eq - 1]
+                .iter()
+                .map(|token| token.to_string())
+                .collect::<Vec<_>>()
+                .join(" ");
+            let mut value = json!({
+                "kind": def[0].to_string(),
+                "macro_export": macro_export,
+                "vis": vis,
+                "name": def[eq - 1].to_string(),
+                "export": def[eq + 1].to_string(),
+            });
+            if let Some(TokenTree::Group(helpers)) = def.get(eq + 2) {
+                value["helpers"] = helpers
+                    .stream()
+                    .into_iter()
+                    .filter(|token| matches!(token, TokenTree::Ident(_)))
+                    .map(|token| Value::from(token.to_string()))
+                    .collect();
+            }
+            value
+        })
+        .collect()
+}
+
+/// Read the macros exported by the module `wasm`, loaded from `path`.
+///
+/// They are described by the manifest next to the module if there is one,
+/// whose names are checked to be usable as identifiers. Otherwise, each
+/// function exported by the module is a function-like macro, so that modules
+/// written by hand or in other languages may be used. See `raw_macro_name`
+/// for the names these macros are given.
+pub fn read(path: &Path, wasm: &[u8]) -> Result<Precompiled, String> {
+    let manifest_path = path.with_extension("json");
+    if !manifest_path.is_file() {
+        let mut krate = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy())
+            .unwrap_or_default()
+            .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+        if !is_ident(&krate) {
+            krate.insert(0, '_');
+        }
+        let macros = exported_functions(wasm)?
+            .into_iter()
+            .filter(|name| is_ident(name))
+            .filter_map(|export| Some(format!("bang {} = {},", raw_macro_name(&export)?, export)))
+            .collect::<String>();
+        return Ok(Precompiled {
+            krate,
+            hash: None,
+            macros: macros.parse().map_err(|_| "invalid export names".to_owned())?,
+            manifest: None,
+        });
+    }
+
+    let manifest = fs::read_to_string(&manifest_path).map_err(|err| err.to_string())?;
+    let manifest = serde_json::from_str::<Value>(&manifest)
+        .map_err(|err| format!("invalid manifest `{}`: {}", manifest_path.display(), err))?;
+    let invalid = |what: &str, value: &Value| {
+        format!("invalid manifest `{}`: invalid {} {}", manifest_path.display(), what, value)
+    };
+    let krate = manifest["crate"].as_str().unwrap_or_default();
+    if !is_ident(krate) {
+        return Err(invalid("crate name", &manifest["crate"]));
+    }
+    let mut macros = String::new();
+    for mac in manifest["macros"].as_array().into_iter().flatten() {
+        let field = |name| mac[name].as_str().unwrap_or_default();
+        if !matches!(field("kind"), "bang" | "attr" | "derive") {
+            return Err(invalid("macro kind", &mac["kind"]));
+        }
+        if !is_vis(field("vis")) {
+            return Err(invalid("visibility", &mac["vis"]));
+        }
+        for name in &["name", "export"] {
+            if !is_ident(field(name)) {
+                return Err(invalid(&format!("macro {}", name), &mac[*name]));
+            }
+        }
+        macros.push_str(&format!(
+            "{} {} {} {} = {}",
+            field("kind"),
+            if mac["macro_export"] == true { "#[macro_export]" } else { "" },
+            field("vis"),
+            field("name"),
+            field("export")
+        ));
+        if let Some(helpers) = mac["helpers"].as_array() {
+            let helpers = helpers.iter().filter_map(Value::as_str).collect::<Vec<_>>();
+            if let Some(helper) = helpers.iter().find(|helper| !is_ident(helper)) {
+                return Err(invalid("helper attribute", &Value::from(*helper)));
+            }
+            macros.push_str(&format!(" [{}]", helpers.join(", ")));
+        }
+        macros.push(',');
+    }
+    Ok(Precompiled {
+        krate: krate.to_owned(),
+        hash: manifest["hash"].as_str().map(str::to_owned),
+        macros: macros
+            .parse()
+            .map_err(|_| format!("invalid manifest `{}`", manifest_path.display()))?,
+        manifest: Some(manifest_path),
+    })
+}
+
+/// Keywords of the 2018 edition, which can't be used as identifiers.
+const KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in",
+    "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+    "return", "self", "Self", "static", "struct", "super", "trait", "true", "try", "type",
+    "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+/// Whether `name` may be used as an identifier.
+/// The name of the macro for the function `export` of a module without a
+/// manifest, if it is a macro.
+///
+/// Macros exported by a macro crate are named as in the crate, without the
+/// path of the module they are defined in. Other functions used by `ctrs`
+/// itself aren't macros, and any other function keeps its name. Functions
+/// whose names would give a macro a keyword as its name are skipped.
+fn raw_macro_name(export: &str) -> Option<&str> {
+    let name = match export.strip_prefix("__ctrs_export_") {
+        Some(path) => path.rsplit("__").next().unwrap_or(path),
+        None if export.starts_with("__ctrs_") => return None,
+        None => export,
+    };
+    if is_ident(name) {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+fn is_ident(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+        && name != "_"
+        && !KEYWORDS.contains(&name)
+}
+
+/// Whether `vis` is a visibility, as written into manifests: empty, `crate`,
+/// `pub`, or `pub` restricted to a path such as `pub (in crate :: a)`.
+fn is_vis(vis: &str) -> bool {
+    let restriction = match vis.strip_prefix("pub") {
+        Some(rest) if rest.trim().is_empty() => return true,
+        Some(rest) => rest.trim(),
+        None => return vis.is_empty() || vis == "crate",
+    };
+    let path = match restriction.strip_prefix('(').and_then(|rest| rest.strip_suffix(')')) {
+        Some(path) => path.trim(),
+        None => return false,
+    };
+    match path.strip_prefix("in ") {
+        Some(path) => path.split("::").map(str::trim).enumerate().all(|(idx, segment)| {
+            is_ident(segment) || matches!(segment, "self" | "super") || (idx == 0 && segment == "crate")
+        }),
+        None => matches!(path, "crate" | "self" | "super"),
+    }
+}
+
+/// Find the names of the functions exported by a wasm module.
+pub fn exported_functions(wasm: &[u8]) -> Result<Vec<String>, String> {
+    let invalid = || "invalid wasm module".to_owned();
+    if wasm.get(..4) != Some(b"\0asm") {
+        return Err(invalid());
+    }
+
+    let mut pos = 8;
+    while pos < wasm.len() {
+        let id = wasm[pos];
+        pos += 1;
+        let size = read_u32(wasm, &mut pos).ok_or_else(invalid)? as usize;
+        let end = pos.checked_add(size).filter(|&end| end <= wasm.len()).ok_or_else(invalid)?;
+
+        // The export section.
+        if id == 7 {
+            let mut names = Vec::new();
+            let count = read_u32(wasm, &mut pos).ok_or_else(invalid)?;
+            for _ in 0..count {
+                let len = read_u32(wasm, &mut pos).ok_or_else(invalid)? as usize;
+                let name = wasm.get(pos..pos + len).ok_or_else(invalid)?;
+                pos += len;
+                let kind = *wasm.get(pos).ok_or_else(invalid)?;
+                pos += 1;
+                read_u32(wasm, &mut pos).ok_or_else(invalid)?;
+                if kind == 0 {
+                    names.push(String::from_utf8_lossy(name).into_owned());
+                }
+            }
+            return Ok(names);
+        }
+        pos = end;
+    }
+    Ok(Vec::new())
+}
+
+/// Read an unsigned LEB128 integer.
+fn read_u32(wasm: &[u8], pos: &mut usize) -> Option<u32> {
+    let mut result = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = *wasm.get(*pos)?;
+        *pos += 1;
+        // Only the low 4 bits of the fifth byte fit.
+        if shift == 28 && byte & 0x70 != 0 {
+            return None;
+        }
+        result |= u32::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(result);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A module with a type section, and an export section exporting the
+    /// function `run` and a memory.
+    const MODULE: &[u8] = b"\0asm\x01\0\0\0\
+        \x01\x04\x01\x60\0\0\
+        \x07\x10\x02\x03run\0\0\x06memory\x02\0";
+
+    #[test]
+    fn exports() {
+        assert_eq!(exported_functions(MODULE).unwrap(), ["run"]);
+        assert_eq!(exported_functions(&MODULE[..14]).unwrap(), Vec::<String>::new());
+        assert!(exported_functions(&MODULE[..MODULE.len() - 1]).is_err());
+        assert!(exported_functions(b"\0wasm").is_err());
+    }
+
+    #[test]
+    fn raw_macro_names() {
+        assert_eq!(raw_macro_name("__ctrs_export_shout"), Some("shout"));
+        assert_eq!(raw_macro_name("__ctrs_export_util__json__parse"), Some("parse"));
+        assert_eq!(raw_macro_name("parse__json"), Some("parse__json"));
+        assert_eq!(raw_macro_name("__ctrs_host_read_files"), None);
+        assert_eq!(raw_macro_name("__ctrs_export_self"), None);
+    }
+
+    #[test]
+    fn leb128() {
+        let read = |bytes: &[u8]| {
+            let mut pos = 0;
+            read_u32(bytes, &mut pos).map(|value| (value, pos))
+        };
+        assert_eq!(read(&[0x05, 0xff]), Some((5, 1)));
+        assert_eq!(read(&[0xe5, 0x8e, 0x26]), Some((624_485, 3)));
+        assert_eq!(read(&[0xff, 0xff, 0xff, 0xff, 0x0f]), Some((u32::MAX, 5)));
+        assert_eq!(read(&[0x80, 0x80, 0x80, 0x80, 0x10]), None);
+        assert_eq!(read(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x00]), None);
+        assert_eq!(read(&[0x80]), None);
+    }
+}
@@ -0,0 +1,199 @@
+//! Native backend, which builds macro crates for the host and loads them as
+//! dynamic libraries, rather than interpreting their wasm modules.
+//!
+//! Macro crates are linked against the fallback implementation of
+//! `proc_macro2`, built with `procmacro2_semver_exempt` for `Span::join`, so
+//! tokens cross into the library as text. Input is printed
+//! by `SourceMap`, and the library reports where in that text the span of each
+//! output token points, so that spans can be mapped back to the input.
+
+use crate::cache::Cache;
+use crate::diagnostics::{self, SourceMap};
+use libloading::Library;
+use proc_macro::{Group, Span, TokenStream, TokenTree};
+use std::collections::BTreeMap;
+use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+use std::fs;
+use std::io::Write;
+use std::os::raw::c_void;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::slice;
+use std::sync::Mutex;
+use tempdir::TempDir;
+
+/// Libraries loaded by this process, keyed by the hash of the wasm module of
+/// the same macro crate. Libraries are never unloaded.
+static LIBRARIES: Mutex<BTreeMap<String, &'static Library>> = Mutex::new(BTreeMap::new());
+
+/// Directories of native builds made while caching is disabled, keyed by
+/// module hash.
+static UNCACHED: Mutex<BTreeMap<String, PathBuf>> = Mutex::new(BTreeMap::new());
+
+/// Added to the source of every macro crate built natively.
+const SUPPORT: &str = include_str!("../native/support.rs");
+
+/// Build a macro crate natively, and load it for use in place of the wasm
+/// module with hash `hash`.
+pub fn build(name: &str, source: &str, hash: &str) -> Result<(), String> {
+    let dir = match Cache::open() {
+        Some(cache) => cache.native_dir().join(hash),
+        None => {
+            let tmp = TempDir::new("ctrs_native").map_err(|err| err.to_string())?;
+            let dir = tmp.into_path();
+            UNCACHED.lock().unwrap().insert(hash.to_owned(), dir.clone());
+            dir
+        }
+    };
+    let lib = dir.join(format!("{}{}{}", DLL_PREFIX, name, DLL_SUFFIX));
+    if !lib.is_file() {
+        run_rustc(name, source, &dir, &lib)?;
+    }
+    load(hash, &lib).map(|_| ())
+}
+
+/// Build a macro crate into `dir`. The library is built in a temporary
+/// directory and renamed into place, as other processes may be building it
+/// too.
+fn run_rustc(name: &str, source: &str, dir: &Path, lib: &Path) -> Result<(), String> {
+    let parent = dir.parent().unwrap();
+    fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+    let tmp = TempDir::new_in(parent, "build").map_err(|err| err.to_string())?;
+    let staged = tmp.path().join("staged");
+    fs::create_dir(&staged).map_err(|err| err.to_string())?;
+
+    let runtime = env!("CTRS_NATIVE_DIR");
+    let mut cmd = Command::new(env!("RUSTC"));
+    cmd.stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .args([
+            "--edition",
+            "2018",
+            "--crate-type",
+            "cdylib",
+            "--crate-name",
+            name,
+            "-C",
+            "opt-level=2",
+            "--cap-lints",
+            "allow",
+            "--error-format",
+            "json",
+        ])
+        .arg("-o")
+        .arg(staged.join(lib.file_name().unwrap()))
+        .arg("-L")
+        .arg(format!("dependency={}/deps", runtime));
+    for krate in &["unicode_xid", "proc_macro2", "syn", "quote"] {
+        cmd.arg("--extern")
+            .arg(format!("{0}={1}/lib{0}.rlib", krate, runtime));
+    }
+    let mut child = cmd.arg("-").spawn().map_err(|err| err.to_string())?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(format!("{}\n{}", source, SUPPORT).as_bytes())
+        .map_err(|err| err.to_string())?;
+    let output = child.wait_with_output().map_err(|err| err.to_string())?;
+
+    if !output.status.success() {
+        let message = format!("macro crate `{}` could not be built natively", name);
+        return Err(message + &diagnostics::rendered_errors(&output.stderr));
+    }
+
+    if fs::rename(&staged, dir).is_err() && !lib.is_file() {
+        return Err(format!("failed to store native build of `{}`", name));
+    }
+    Ok(())
+}
+
+fn load(hash: &str, path: &Path) -> Result<&'static Library, String> {
+    let mut libraries = LIBRARIES.lock().unwrap();
+    if let Some(library) = libraries.get(hash) {
+        return Ok(library);
+    }
+    let library = Library::new(path).map_err(|err| err.to_string())?;
+    let library = &*Box::leak(Box::new(library));
+    libraries.insert(hash.to_owned(), library);
+    Ok(library)
+}
+
+/// Find the native build for the wasm module with hash `hash`, which may have
+/// been built by another compiler sharing the cache.
+fn library(hash: &str) -> Option<&'static Library> {
+    if let Some(library) = LIBRARIES.lock().unwrap().get(hash) {
+        return Some(library);
+    }
+    let dir = match UNCACHED.lock().unwrap().get(hash) {
+        Some(dir) => dir.clone(),
+        None => Cache::open()?.native_dir().join(hash),
+    };
+    let path = fs::read_dir(dir).ok()?.next()?.ok()?.path();
+    load(hash, &path).ok()
+}
+
+/// Run the export `func` of the native build for the wasm module with hash
+/// `hash`. Returns `None` if there is no native build, or the message of a
+/// panic if the macro panicked.
+pub fn run(hash: &str, func: &str, input: TokenStream) -> Option<Result<TokenStream, String>> {
+    unsafe extern "C" fn collect(ctx: *mut c_void, data: *const u8, len: usize) {
+        (*(ctx as *mut Vec<u8>)).extend_from_slice(slice::from_raw_parts(data, len));
+    }
+    type Run = unsafe extern "C" fn(
+        *const c_void,
+        *const u8,
+        usize,
+        unsafe extern "C" fn(*mut c_void, *const u8, usize),
+        *mut c_void,
+    ) -> u32;
+
+    let library = library(hash)?;
+    let source = SourceMap::new(input);
+    let input = &source.source;
+    let mut output = Vec::new();
+    let status = unsafe {
+        let run = library.get::<Run>(b"__ctrs_native_run\0").ok()?;
+        let func = library.get::<*const c_void>(format!("{}\0", func).as_bytes()).ok()?;
+        run(
+            *func,
+            input.as_ptr(),
+            input.len(),
+            collect,
+            &mut output as *mut Vec<u8> as *mut c_void,
+        )
+    };
+    let output = String::from_utf8_lossy(&output);
+    if status != 0 {
+        return Some(Err(output.into_owned()));
+    }
+
+    let mut parts = output.splitn(2, '\n');
+    let offsets = parts.next().unwrap_or_default();
+    let tokens = match parts.next().unwrap_or_default().parse::<TokenStream>() {
+        Ok(tokens) => tokens,
+        Err(_) => return Some(Err("macro produced invalid tokens".to_owned())),
+    };
+    let mut spans = offsets.split_whitespace().map(|offset| {
+        source.span_at(offset.parse().ok()?)
+    });
+    Some(Ok(respan(tokens, &mut spans)))
+}
+
+/// Give each token the span of the input token its span pointed to within
+/// the library, in the order they were printed.
+fn respan(stream: TokenStream, spans: &mut dyn Iterator<Item = Option<Span>>) -> TokenStream {
+    stream
+        .into_iter()
+        .map(|mut token| {
+            let span = spans.next().flatten();
+            if let TokenTree::Group(group) = &token {
+                token = TokenTree::Group(Group::new(group.delimiter(), respan(group.stream(), spans)));
+            }
+            if let Some(span) = span {
+                token.set_span(span);
+            }
+            token
+        })
+        .collect()
+}
@@ -0,0 +1,71 @@
+//! Capture of text written to stderr while a macro runs.
+//!
+//! When a macro panics, its module prints the panic message to stderr through
+//! `watt_print_panic` before trapping, so the message is only known to the
+//! host by capturing what is written there.
+
+/// Run `f`, returning its result along with everything written to stderr
+/// meanwhile, which is not passed through.
+///
+/// Stderr is captured by pointing file descriptor 2 at a file, which swaps it
+/// for the whole process: anything written to stderr by other threads while
+/// `f` runs is captured too. The file is opened once per process, unlinked
+/// right away, and reused by every capture.
+#[cfg(unix)]
+pub fn capture<T>(f: impl FnOnce() -> T) -> (T, String) {
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+    use std::os::unix::io::AsRawFd;
+    use std::process;
+    use std::sync::Mutex;
+
+    // File descriptors are shared by every thread, so captures are
+    // serialized, and hold the file while they run.
+    static FILE: Mutex<Option<Option<File>>> = Mutex::new(None);
+    let mut lock = FILE.lock().unwrap_or_else(|err| err.into_inner());
+    let file = lock.get_or_insert_with(|| {
+        let path = env::temp_dir().join(format!("ctrs_stderr_{}", process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .ok()?;
+        let _ = fs::remove_file(&path);
+        Some(file)
+    });
+    let file = match file {
+        Some(file) => file,
+        None => return (f(), String::new()),
+    };
+    if file.set_len(0).and_then(|_| file.seek(SeekFrom::Start(0))).is_err() {
+        return (f(), String::new());
+    }
+
+    let _ = io::stderr().flush();
+    let saved = unsafe { libc::dup(2) };
+    if saved < 0 {
+        return (f(), String::new());
+    }
+    if unsafe { libc::dup2(file.as_raw_fd(), 2) } < 0 {
+        unsafe { libc::close(saved) };
+        return (f(), String::new());
+    }
+    let result = f();
+    let _ = io::stderr().flush();
+    unsafe {
+        libc::dup2(saved, 2);
+        libc::close(saved);
+    }
+
+    let mut text = String::new();
+    let _ = file.seek(SeekFrom::Start(0)).and_then(|_| file.read_to_string(&mut text));
+    (result, text)
+}
+
+#[cfg(not(unix))]
+pub fn capture<T>(f: impl FnOnce() -> T) -> (T, String) {
+    (f(), String::new())
+}
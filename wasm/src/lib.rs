@@ -1,104 +1,1093 @@
 //! Internal implementation crate for `ctrs`
 
 #![deny(warnings)]
+// When built natively, as macro crates are by the native backend, the exports
+// take `TokenStream`s by value.
+#![cfg_attr(not(target_arch = "wasm32"), allow(improper_ctypes_definitions))]
 
 use proc_macro2::{TokenStream, TokenTree};
 use syn::parse::{Parse, ParseStream};
 use syn::punctuated::Punctuated;
+use syn::visit_mut::{self, VisitMut};
 use syn::*;
-use quote::quote;
+use quote::{format_ident, quote, ToTokens};
+
+/// Report panics to the host. Natively, they unwind as usual.
+fn set_panic_hook() {
+    #[cfg(target_arch = "wasm32")]
+    proc_macro2::set_wasm_panic_hook();
+}
+
+/// The kind of a macro exported from a macro crate, along with any
+/// kind-specific information needed to generate its trampoline.
+enum MacroKind {
+    Bang,
+    Attr,
+    Derive(Punctuated<Ident, Token![,]>),
+}
+
+/// A single macro exported by a macro crate.
+///
+/// Passed from `ctrs` to `build_result` as `bang name = func`,
+/// `attr name = func` or `derive Name = func [helpers]`, with
+/// `#[macro_export]` and the visibility between the kind and the name.
+struct MacroDef {
+    kind: MacroKind,
+    /// Whether the macro function is marked `#[macro_export]`.
+    export: bool,
+    /// Visibility of the macro function, narrowed to that of the modules it
+    /// is within, which the macro is given too.
+    vis: Visibility,
+    name: Ident,
+    func: Ident,
+}
+
+impl Parse for MacroDef {
+    fn parse(stream: ParseStream) -> Result<Self> {
+        let kind = stream.parse::<Ident>()?;
+        let export = stream
+            .call(Attribute::parse_outer)?
+            .iter()
+            .any(|attr| attr.path.is_ident("macro_export"));
+        let vis = stream.parse::<Visibility>()?;
+        let name = stream.parse::<Ident>()?;
+        stream.parse::<Token![=]>()?;
+        let func = stream.parse::<Ident>()?;
+        let kind = if kind == "bang" {
+            MacroKind::Bang
+        } else if kind == "attr" {
+            MacroKind::Attr
+        } else if kind == "derive" {
+            let helpers;
+            bracketed!(helpers in stream);
+            MacroKind::Derive(helpers.parse_terminated(Ident::parse)?)
+        } else {
+            return Err(Error::new(kind.span(), "unknown macro kind"));
+        };
+        Ok(MacroDef {
+            kind,
+            export,
+            vis,
+            name,
+            func,
+        })
+    }
+}
+
+impl ToTokens for MacroDef {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        let export = if self.export {
+            quote!(#[macro_export])
+        } else {
+            quote!()
+        };
+        let vis = &self.vis;
+        let name = &self.name;
+        let func = &self.func;
+        tokens.extend(match &self.kind {
+            MacroKind::Bang => quote!(bang #export #vis #name = #func),
+            MacroKind::Attr => quote!(attr #export #vis #name = #func),
+            MacroKind::Derive(helpers) => quote!(derive #export #vis #name = #func [#helpers]),
+        });
+    }
+}
 
 struct BuildResult {
-    _name: Ident,
+    name: Ident,
     wasm: TokenTree,
-    macros: Punctuated<Ident, Token![,]>,
+    hash: TokenTree,
+    macros: Punctuated<MacroDef, Token![,]>,
 }
 
 impl Parse for BuildResult {
     fn parse(stream: ParseStream) -> Result<Self> {
         Ok(BuildResult {
-            _name: stream.parse()?,
+            name: stream.parse()?,
             wasm: stream.parse()?,
-            macros: stream.parse_terminated(Ident::parse)?,
+            hash: stream.parse()?,
+            macros: stream.parse_terminated(MacroDef::parse)?,
         })
     }
 }
 
 #[no_mangle]
 pub extern "C" fn build_result(input: TokenStream) -> TokenStream {
-    proc_macro2::set_wasm_panic_hook();
+    set_panic_hook();
 
     let input = syn::parse2::<BuildResult>(input).unwrap();
 
+    let krate = &input.name;
     let wasm = &input.wasm;
+    let hash = &input.hash;
     let mut result = TokenStream::new();
-    for macro_name in &input.macros {
+
+    // Exported macros may be used from crates which don't depend on `ctrs`,
+    // so refer to it through a re-export. Exported macros can only refer to
+    // items at the crate root, so check that the re-export is there, as
+    // otherwise every use of the macros fails to resolve it.
+    let reexport = format_ident!("__ctrs_reexport_{}", krate);
+    let has_export = input.macros.iter().any(|mac| mac.export);
+    if has_export {
+        let message = format!(
+            "macro crate `{}` has `#[macro_export]` macros, so must be declared at the crate root",
+            krate
+        );
         result.extend(quote! {
-            macro_rules! #macro_name {
-                ($($t:tt)*) => {
-                    ::ctrs::ctrs! { __eval_wasm__ #macro_name #wasm $($t)* }
-                };
+            #[doc(hidden)]
+            pub use ::ctrs as #reexport;
+            const _: () = {
+                let path = module_path!().as_bytes();
+                let mut i = 0;
+                while i < path.len() {
+                    if path[i] == b':' {
+                        panic!(#message);
+                    }
+                    i += 1;
+                }
+            };
+        });
+    }
+    let ctrs_path = |export: bool| {
+        if export {
+            quote!($crate::#reexport::ctrs!)
+        } else {
+            quote!(::ctrs::ctrs!)
+        }
+    };
+
+    // The encoded module is only included once, in a macro which passes it to
+    // the host when an invocation refers to a module it has not yet loaded.
+    // If any macro may be used outside of the `ctrs!` invocation's module, it
+    // is exported too, so it can be referred to from anywhere.
+    //
+    // Macro-expanded exported macros can't be referred to by path within the
+    // crate which defines them, so there the host instead looks the module up
+    // by hash in its cache, where it is stored whenever it is loaded. This is
+    // only forwarded to if the cache is disabled, such as when an IDE re-runs
+    // an invocation in a new process without re-running this one.
+    let module = format_ident!("__ctrs_module_{}", krate);
+    let has_restricted = input
+        .macros
+        .iter()
+        .any(|mac| mac.export || !matches!(mac.vis, Visibility::Inherited));
+    let export_attr = if has_restricted {
+        quote!(#[macro_export])
+    } else {
+        quote!()
+    };
+    let module_ctrs = ctrs_path(has_export);
+    result.extend(quote! {
+        #[doc(hidden)]
+        #[allow(unused_macros)]
+        #export_attr
+        macro_rules! #module {
+            ($($t:tt)*) => {
+                #module_ctrs { __load_wasm__ #wasm $($t)* }
+            };
+        }
+    });
+    for mac in &input.macros {
+        let vis = &mac.vis;
+        let name = &mac.name;
+        let func = &mac.func;
+        let ctrs = ctrs_path(mac.export);
+        let module = if mac.export || !matches!(vis, Visibility::Inherited) {
+            quote!($crate::#module)
+        } else {
+            quote!(#module)
+        };
+        let export_attr = if mac.export {
+            quote!(#[macro_export])
+        } else {
+            quote!()
+        };
+        result.extend(match &mac.kind {
+            // Attribute macros receive `(args) item`, as packed by
+            // `#[ctrs::attr(..)]`.
+            MacroKind::Bang | MacroKind::Attr => quote! {
+                #export_attr
+                macro_rules! #name {
+                    ($($t:tt)*) => {
+                        #ctrs { __eval_wasm__ [#module] #krate #name #func #hash $($t)* }
+                    };
+                }
+            },
+            // Invoked by `#[ctrs::derive(..)]` with the remaining derives to
+            // apply and the helper attributes of those already applied. Each
+            // derive sees the original item; helper attributes are stripped
+            // only after the last derive has run.
+            MacroKind::Derive(helpers) => {
+                let helpers = helpers.iter();
+                quote! {
+                    #export_attr
+                    macro_rules! #name {
+                        (__ctrs_derive__ [$($rest:tt)*] [$($seen:tt)*] $($t:tt)*) => {
+                            #ctrs { __eval_wasm__ [#module] #krate #name #func #hash $($t)* }
+                            #ctrs { __strip_helpers__ [#(#helpers,)* $($seen)*] [$($rest)*] $($t)* }
+                        };
+                    }
+                }
             }
         });
+
+        // Macros with restricted visibility are made available by path, like
+        // other items. `pub` alone doesn't export a macro, so such macros are
+        // only available after the `ctrs!` invocation, as private ones are.
+        // Exported macros are already available by path from the crate root.
+        if let (false, Visibility::Restricted(_) | Visibility::Crate(_)) = (mac.export, vis) {
+            result.extend(quote!(#vis use #name;));
+        }
     }
     result
 }
 
+/// The narrower of the visibility `outer` of a module and `inner` of an item
+/// within it, which is the visibility the item effectively has. Of two
+/// restricted visibilities, the item's own is kept.
+fn narrowest(outer: &Visibility, inner: &Visibility) -> Visibility {
+    fn rank(vis: &Visibility) -> u8 {
+        match vis {
+            Visibility::Inherited => 0,
+            Visibility::Restricted(vis) if vis.path.is_ident("self") => 0,
+            Visibility::Restricted(vis) if vis.path.is_ident("crate") => 2,
+            Visibility::Restricted(_) => 1,
+            Visibility::Crate(_) => 2,
+            Visibility::Public(_) => 3,
+        }
+    }
+    if rank(outer) < rank(inner) {
+        outer.clone()
+    } else {
+        inner.clone()
+    }
+}
+
+struct DeriveArgs {
+    derives: Punctuated<Path, Token![,]>,
+    item: TokenStream,
+}
+
+impl Parse for DeriveArgs {
+    fn parse(stream: ParseStream) -> Result<Self> {
+        let derives;
+        parenthesized!(derives in stream);
+        Ok(DeriveArgs {
+            derives: derives.parse_terminated(Path::parse)?,
+            item: stream.parse()?,
+        })
+    }
+}
+
+/// Hands an item annotated with `#[ctrs::derive(..)]` to the first derive in
+/// the list.
+#[no_mangle]
+pub extern "C" fn derive(input: TokenStream) -> TokenStream {
+    set_panic_hook();
+
+    let input = syn::parse2::<DeriveArgs>(input).unwrap();
+    match chain_derive(input.derives.into_iter(), &Punctuated::new(), &input.item) {
+        Some(next) => next,
+        None => input.item,
+    }
+}
+
+/// Hands the item to the next derive in the chain, along with the helper
+/// attributes of the derives which have already run, if any derives remain.
+fn chain_derive(
+    mut derives: impl Iterator<Item = Path>,
+    helpers: &Punctuated<Ident, Token![,]>,
+    item: &TokenStream,
+) -> Option<TokenStream> {
+    let first = derives.next()?;
+    let rest = derives.collect::<Punctuated<Path, Token![,]>>();
+    let helpers = helpers.iter();
+    Some(quote!(#first! { __ctrs_derive__ [#rest] [#(#helpers,)*] #item }))
+}
+
+struct AttrArgs {
+    path: Path,
+    args: TokenStream,
+    item: TokenStream,
+}
+
+impl Parse for AttrArgs {
+    fn parse(stream: ParseStream) -> Result<Self> {
+        let attr;
+        parenthesized!(attr in stream);
+        let path = attr.parse::<Path>()?;
+        let args = if attr.is_empty() {
+            TokenStream::new()
+        } else {
+            let args;
+            parenthesized!(args in attr);
+            args.parse()?
+        };
+        Ok(AttrArgs {
+            path,
+            args,
+            item: stream.parse()?,
+        })
+    }
+}
+
+/// Hands an item annotated with `#[ctrs::attr(my_attr(args))]` to the
+/// attribute macro `my_attr`.
+#[no_mangle]
+pub extern "C" fn attr(input: TokenStream) -> TokenStream {
+    set_panic_hook();
+
+    let input = syn::parse2::<AttrArgs>(input).unwrap();
+    let path = &input.path;
+    let args = &input.args;
+    let item = &input.item;
+    quote!(#path! { (#args) #item })
+}
+
+struct StripHelpersArgs {
+    helpers: Punctuated<Ident, Token![,]>,
+    rest: Punctuated<Path, Token![,]>,
+    item: TokenStream,
+}
+
+impl Parse for StripHelpersArgs {
+    fn parse(stream: ParseStream) -> Result<Self> {
+        let helpers;
+        bracketed!(helpers in stream);
+        let rest;
+        bracketed!(rest in stream);
+        Ok(StripHelpersArgs {
+            helpers: helpers.parse_terminated(Ident::parse)?,
+            rest: rest.parse_terminated(Path::parse)?,
+            item: stream.parse()?,
+        })
+    }
+}
+
+struct StripHelpers<'a>(&'a Punctuated<Ident, Token![,]>);
+
+impl StripHelpers<'_> {
+    fn strip(&self, attrs: &mut Vec<Attribute>) {
+        attrs.retain(|attr| !self.0.iter().any(|helper| attr.path.is_ident(helper)));
+    }
+}
+
+impl VisitMut for StripHelpers<'_> {
+    fn visit_derive_input_mut(&mut self, node: &mut DeriveInput) {
+        self.strip(&mut node.attrs);
+        visit_mut::visit_derive_input_mut(self, node);
+    }
+
+    fn visit_variant_mut(&mut self, node: &mut Variant) {
+        self.strip(&mut node.attrs);
+        visit_mut::visit_variant_mut(self, node);
+    }
+
+    fn visit_field_mut(&mut self, node: &mut Field) {
+        self.strip(&mut node.attrs);
+        visit_mut::visit_field_mut(self, node);
+    }
+}
+
+/// Passes an item on unchanged to the next derive in the chain once a derive
+/// has run. After the last derive, removes the helper attributes of all of
+/// them from the item.
+#[no_mangle]
+pub extern "C" fn strip_helpers(input: TokenStream) -> TokenStream {
+    set_panic_hook();
+
+    let input = syn::parse2::<StripHelpersArgs>(input).unwrap();
+    if let Some(next) = chain_derive(input.rest.into_iter(), &input.helpers, &input.item) {
+        return next;
+    }
+    let mut item = syn::parse2::<DeriveInput>(input.item).unwrap();
+    StripHelpers(&input.helpers).visit_derive_input_mut(&mut item);
+    item.into_token_stream()
+}
+
+/// A dependency declared in the `macro crate` header, as `name = "path"`.
+#[derive(Debug)]
+struct DepDecl {
+    name: Ident,
+    path: LitStr,
+}
+
+impl Parse for DepDecl {
+    fn parse(stream: ParseStream) -> Result<Self> {
+        let name = stream.parse()?;
+        stream.parse::<Token![=]>()?;
+        let path = stream.parse()?;
+        Ok(DepDecl { name, path })
+    }
+}
+
+impl ToTokens for DepDecl {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        // The host reads the path as a plain string literal, so raw strings
+        // and escapes are resolved here.
+        let DepDecl { name, path } = self;
+        let path = LitStr::new(&path.value(), path.span());
+        tokens.extend(quote!(#name = #path));
+    }
+}
+
 #[derive(Debug)]
 struct CtrsInput {
+    /// Contents of out-of-line modules read by the host, keyed by the path of
+    /// their file. These are passed as `["path" { .. }, ..]` before the
+    /// `macro crate` header.
+    modules: Vec<(LitStr, TokenStream)>,
     name: Ident,
+    deps: Punctuated<DepDecl, Token![,]>,
+    /// Path of the file containing the crate's source, if it was declared as
+    /// `macro crate name = "path";`.
+    source: Option<LitStr>,
+    /// Inner attributes of the crate, which follow the header.
+    attrs: Vec<Attribute>,
     items: Vec<Item>,
 }
 
 impl Parse for CtrsInput {
     fn parse(stream: ParseStream) -> Result<Self> {
+        let mut modules = Vec::new();
+        if stream.peek(token::Bracket) {
+            let content;
+            bracketed!(content in stream);
+            while !content.is_empty() {
+                let key = content.parse::<LitStr>()?;
+                let tokens;
+                braced!(tokens in content);
+                modules.push((key, tokens.parse()?));
+                content.parse::<Option<Token![,]>>()?;
+            }
+        }
+
         stream.parse::<Token![macro]>()?;
         stream.parse::<Token![crate]>()?;
         let name = stream.parse::<Ident>()?;
+        let mut deps = Punctuated::new();
+        if stream.peek(token::Paren) {
+            let content;
+            parenthesized!(content in stream);
+            deps = content.parse_terminated(DepDecl::parse)?;
+        }
+        let mut source = None;
+        if stream.parse::<Option<Token![=]>>()?.is_some() {
+            source = Some(stream.parse()?);
+        }
         stream.parse::<Token![;]>()?;
 
         let mut items = <Vec<Item>>::new();
+        if source.is_some() && !stream.is_empty() {
+            return Err(stream.error("unexpected items after external macro crate"));
+        }
+        let attrs = stream.call(Attribute::parse_inner)?;
         while !stream.is_empty() {
             items.push(stream.parse()?);
         }
 
-        Ok(CtrsInput {name, items})
+        Ok(CtrsInput { modules, name, deps, source, attrs, items })
     }
 }
 
-#[no_mangle]
-pub extern "C" fn ctrs(input: TokenStream) -> TokenStream {
-    proc_macro2::set_wasm_panic_hook();
+/// Parses `#[proc_macro_derive(Name, attributes(helper, ...))]`.
+fn parse_derive_attr(attr: &Attribute) -> Result<(Ident, Punctuated<Ident, Token![,]>)> {
+    attr.parse_args_with(|stream: ParseStream| {
+        let name = stream.parse::<Ident>()?;
+        let mut helpers = Punctuated::new();
+        if stream.parse::<Option<Token![,]>>()?.is_some() && !stream.is_empty() {
+            let attributes = stream.parse::<Ident>()?;
+            if attributes != "attributes" {
+                return Err(Error::new(attributes.span(), "expected `attributes`"));
+            }
+            let content;
+            parenthesized!(content in stream);
+            helpers = content.parse_terminated(Ident::parse)?;
+            stream.parse::<Option<Token![,]>>()?;
+        }
+        Ok((name, helpers))
+    })
+}
 
-    let mut input = syn::parse2::<CtrsInput>(input).unwrap();
+/// Generate a wasm export which calls the macro function `func`, unpacking
+/// the `(args) item` stream built by `#[ctrs::attr(..)]` for attribute macros,
+/// and converting errors to `compile_error!` invocations.
+///
+/// The export is placed in the same module as `func`, at `path`, which is
+/// included in its name to keep it unique within the crate.
+fn export(kind: &MacroKind, path: &[&Ident], func: &Ident) -> (Ident, Item) {
+    let mut segments = path.iter().map(|module| module.to_string()).collect::<Vec<_>>();
+    segments.push(func.to_string());
+    let export = Ident::new(&format!("__ctrs_export_{}", segments.join("__")), func.span());
+    let call = match kind {
+        MacroKind::Bang | MacroKind::Derive(_) => quote!(#func(input)),
+        MacroKind::Attr => quote! {{
+            let mut iter = input.into_iter();
+            let args = match iter.next() {
+                Some(::proc_macro2::TokenTree::Group(group)) => group.stream(),
+                _ => panic!("expected attribute arguments"),
+            };
+            #func(args, iter.collect())
+        }},
+    };
+    // Derive macros are only ever expanded where items are expected, so the
+    // host can add items after their output.
+    let items = matches!(kind, MacroKind::Derive(_));
+    let item = parse_quote! {
+        #[no_mangle]
+        pub extern "C" fn #export(input: ::proc_macro2::TokenStream) -> ::proc_macro2::TokenStream {
+            crate::__ctrs_run(input, #items, move |input| crate::__CtrsOutput::into_output(#call))
+        }
+    };
+    (export, item)
+}
 
-    // WOO Let's do some sketchy transformations~
-    let mut macros = <Punctuated<Ident, Token![,]>>::new();
-    for item in &mut input.items {
-        match item {
-            Item::Fn(func) => {
-                // Check for the `proc_macro` attribute, and remove it.
-                let old_len = func.attrs.len();
-                func.attrs.retain(|attr| !attr.path.is_ident("proc_macro"));
-                if old_len > func.attrs.len() {
-                    // Record our macro
-                    // FIXME: Record vis here too?
-                    macros.push(func.sig.ident.clone());
-
-                    // Transform the method into a wasm export.
-                    func.attrs.push(parse_quote!(#[no_mangle]));
-                    func.sig.abi = Some(parse_quote!(extern "C"));
-                    func.block.stmts.insert(0, parse_quote!(::proc_macro2::set_wasm_panic_hook();));
-                    func.vis = parse_quote!(pub);
+/// Support for the exports generated by `export`, included in every macro
+/// crate with exported macros: running the macro function, converting its
+/// return type to its output, and the `ctrs_host` module.
+fn export_support(read_files: &[LitStr]) -> Vec<Item> {
+    vec![
+        // Built natively, panics can't unwind out of the export, so are caught
+        // by the support code which the native backend adds to the crate.
+        parse_quote! {
+            fn __ctrs_run(
+                input: ::proc_macro2::TokenStream,
+                items: bool,
+                f: impl FnOnce(::proc_macro2::TokenStream) -> ::proc_macro2::TokenStream,
+            ) -> ::proc_macro2::TokenStream {
+                #[cfg(target_arch = "wasm32")]
+                ::proc_macro2::set_wasm_panic_hook();
+                let input = crate::ctrs_host::__start(input);
+                #[cfg(target_arch = "wasm32")]
+                let output = f(input);
+                #[cfg(not(target_arch = "wasm32"))]
+                let output = crate::__ctrs_native::catch_unwind(move || f(input));
+                crate::ctrs_host::__finish(output, items)
+            }
+        },
+        host_module(read_files),
+        parse_quote! {
+            trait __CtrsOutput {
+                fn into_output(self) -> ::proc_macro2::TokenStream;
+            }
+        },
+        parse_quote! {
+            impl __CtrsOutput for ::proc_macro2::TokenStream {
+                fn into_output(self) -> ::proc_macro2::TokenStream {
+                    self
+                }
+            }
+        },
+        // Each error combined into a `syn::Error` is reported at its own span.
+        parse_quote! {
+            impl __CtrsOutput for ::std::result::Result<::proc_macro2::TokenStream, ::syn::Error> {
+                fn into_output(self) -> ::proc_macro2::TokenStream {
+                    self.unwrap_or_else(|err| err.to_compile_error())
+                }
+            }
+        },
+    ]
+}
+
+/// The `ctrs_host` module, through which macros read data about the invoking
+/// crate which the host passes ahead of their input, as
+/// `__ctrs_host__ [file "path" "/abs/path" b"contents", allow "PATTERN",
+/// env "NAME" "value", ..]`.
+///
+/// Macros may also emit warnings, notes and help messages.
+///
+/// Anything read is tracked by `include_bytes!` or `option_env!`. These, and
+/// any diagnostics, are passed back to the host ahead of the output, as
+/// `__ctrs_output__ position [tracking] [warning span "message", ..]`, where
+/// `position` is `items` for derive macros, whose output the host may add
+/// items after, and `any` otherwise. Macros may also place them among their
+/// output themselves with `ctrs_host::items()`, which leaves an
+/// `__ctrs_items__` marker for the host to replace.
+///
+/// Files are only passed to crates declaring the paths they read with
+/// `#![ctrs_host(read_files("pattern", ..))]`.
+fn host_module(read_files: &[LitStr]) -> Item {
+    parse_quote! {
+        /// Access to the crate invoking a macro.
+        pub mod ctrs_host {
+            use ::proc_macro2::{Delimiter, Group, Ident, Literal, Span, TokenStream, TokenTree};
+            use ::std::cell::RefCell;
+            use ::std::fmt::Display;
+            use ::std::io;
+
+            #[derive(Default)]
+            struct Host {
+                /// Files which may be read, by their path as written in the
+                /// input, with their absolute path and contents.
+                files: Vec<(String, String, Vec<u8>)>,
+                /// Patterns of the environment variables which may be read.
+                allowed: Vec<String>,
+                /// Values of the allowed environment variables which are set.
+                vars: Vec<(String, String)>,
+                /// Tokens making rustc track what has been read.
+                tracking: TokenStream,
+                /// Diagnostics emitted by the macro.
+                diagnostics: TokenStream,
+                /// Whether `items` has been called.
+                placed: bool,
+            }
+
+            thread_local! {
+                static HOST: RefCell<Host> = RefCell::new(Host::default());
+            }
+
+            /// Patterns of the paths which the macro crate declared with
+            /// `#![ctrs_host(read_files(..))]`.
+            const READ_FILES: &[&str] = &[#(#read_files),*];
+
+            /// Read a file of the invoking crate, by its path relative to the
+            /// crate's `Cargo.toml`.
+            ///
+            /// Only files whose paths match a pattern declared with
+            /// `#![ctrs_host(read_files("pattern", ..))]` can be read, and
+            /// only those within the invoking crate's directory which are
+            /// named by a string literal in the macro's input. The invoking
+            /// crate is rebuilt when a file read by a derive macro changes,
+            /// or by another macro which places `items()` in its output.
+            pub fn read_file(path: &str) -> io::Result<Vec<u8>> {
+                if READ_FILES.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "files can only be read by macro crates declaring `#![ctrs_host(read_files(..))]`",
+                    ));
+                }
+                HOST.with(|host| {
+                    let mut host = host.borrow_mut();
+                    let (abs, contents) = match host.files.iter().find(|(name, ..)| name == path) {
+                        Some((_, abs, contents)) => (abs.clone(), contents.clone()),
+                        None => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::NotFound,
+                                format!(
+                                    "`{}` is not a file within the invoking crate named by a string literal in the macro input and matching `read_files`",
+                                    path
+                                ),
+                            ))
+                        }
+                    };
+                    let tracking = format!("const _: &[u8] = include_bytes!({:?});", abs);
+                    host.tracking.extend(tracking.parse::<TokenStream>().unwrap());
+                    Ok(contents)
+                })
+            }
+
+            /// Read an environment variable of the compiler, like
+            /// `std::env::var`.
+            ///
+            /// Only `CARGO_PKG_*`, `CARGO_MANIFEST_DIR`, `CARGO_CRATE_NAME`,
+            /// `OUT_DIR` and variables listed in `CTRS_ENV_ALLOW` can be read,
+            /// and others are reported as not present. The invoking crate is
+            /// rebuilt when a variable read by a derive macro changes, or by
+            /// another macro which places `items()` in its output.
+            pub fn var(name: &str) -> Result<String, ::std::env::VarError> {
+                HOST.with(|host| {
+                    let mut host = host.borrow_mut();
+                    let allowed = host.allowed.iter().any(|pattern| match pattern.strip_suffix('*') {
+                        Some(prefix) => name.starts_with(prefix),
+                        None => name == pattern,
+                    });
+                    if !allowed {
+                        return Err(::std::env::VarError::NotPresent);
+                    }
+                    let tracking = format!("const _: ::std::option::Option<&str> = option_env!({:?});", name);
+                    host.tracking.extend(tracking.parse::<TokenStream>().unwrap());
+                    host.vars
+                        .iter()
+                        .find(|(var, _)| var == name)
+                        .map(|(_, value)| value.clone())
+                        .ok_or(::std::env::VarError::NotPresent)
+                })
+            }
+
+            /// Items tracking what the macro has read, and showing the
+            /// diagnostics it has emitted, to be placed among its output
+            /// where items are expected.
+            ///
+            /// These are added after the output of derive macros, so are only
+            /// needed by other macros, whose output may not be items. Those
+            /// which read a file or environment variable, or on stable emit a
+            /// diagnostic, without placing `items()` fail with an error, as
+            /// what they read would not be tracked, or the diagnostic shown.
+            pub fn items() -> TokenStream {
+                HOST.with(|host| host.borrow_mut().placed = true);
+                TokenTree::Ident(Ident::new("__ctrs_items__", Span::call_site())).into()
+            }
+
+            /// Emit a warning at `span`, without failing the build.
+            ///
+            /// On stable, diagnostics are shown through items, so macros
+            /// other than derive macros must place `items()` in their output
+            /// to emit them, or they fail with an error.
+            pub fn warning(span: Span, message: impl Display) {
+                emit("warning", span, message);
+            }
+
+            /// Emit a note at `span`.
+            pub fn note(span: Span, message: impl Display) {
+                emit("note", span, message);
+            }
+
+            /// Emit a help message at `span`.
+            pub fn help(span: Span, message: impl Display) {
+                emit("help", span, message);
+            }
+
+            fn emit(level: &str, span: Span, message: impl Display) {
+                HOST.with(|host| {
+                    host.borrow_mut().diagnostics.extend(vec![
+                        TokenTree::Ident(Ident::new(level, Span::call_site())),
+                        TokenTree::Ident(Ident::new("span", span)),
+                        TokenTree::Literal(Literal::string(&message.to_string())),
+                        TokenTree::Punct(::proc_macro2::Punct::new(',', ::proc_macro2::Spacing::Alone)),
+                    ]);
+                });
+            }
+
+            /// The patterns of `READ_FILES`, as string literals, which the
+            /// host reads through the `__ctrs_host_read_files` export.
+            #[doc(hidden)]
+            pub fn __read_files() -> TokenStream {
+                READ_FILES.iter().map(|pattern| TokenTree::Literal(Literal::string(pattern))).collect()
+            }
+
+            #[doc(hidden)]
+            pub fn __start(input: TokenStream) -> TokenStream {
+                let mut host = Host::default();
+                let mut iter = input.clone().into_iter();
+                let entries = match (iter.next(), iter.next()) {
+                    (Some(TokenTree::Ident(marker)), Some(TokenTree::Group(entries)))
+                        if marker == "__ctrs_host__" =>
+                    {
+                        entries
+                    }
+                    _ => {
+                        HOST.with(|slot| *slot.borrow_mut() = host);
+                        return input;
+                    }
+                };
+
+                let parser = |stream: ::syn::parse::ParseStream| -> ::syn::Result<()> {
+                    while !stream.is_empty() {
+                        let kind = stream.parse::<::syn::Ident>()?;
+                        if kind == "file" {
+                            let path = stream.parse::<::syn::LitStr>()?;
+                            let abs = stream.parse::<::syn::LitStr>()?;
+                            let contents = stream.parse::<::syn::LitByteStr>()?;
+                            host.files.push((path.value(), abs.value(), contents.value()));
+                        } else if kind == "allow" {
+                            host.allowed.push(stream.parse::<::syn::LitStr>()?.value());
+                        } else if kind == "env" {
+                            let name = stream.parse::<::syn::LitStr>()?;
+                            let value = stream.parse::<::syn::LitStr>()?;
+                            host.vars.push((name.value(), value.value()));
+                        } else {
+                            return Err(::syn::Error::new(kind.span(), "unknown host data"));
+                        }
+                        stream.parse::<::syn::Token![,]>()?;
+                    }
+                    Ok(())
+                };
+                ::syn::parse::Parser::parse2(parser, entries.stream()).expect("invalid host data");
+                HOST.with(|slot| *slot.borrow_mut() = host);
+                iter.collect()
+            }
+
+            #[doc(hidden)]
+            pub fn __finish(output: TokenStream, items: bool) -> TokenStream {
+                let host = HOST.with(|host| host.replace(Host::default()));
+                if host.tracking.is_empty() && host.diagnostics.is_empty() && !host.placed {
+                    return output;
                 }
+                let position = if items { "items" } else { "any" };
+                let mut result = TokenStream::new();
+                result.extend(vec![
+                    TokenTree::Ident(Ident::new("__ctrs_output__", Span::call_site())),
+                    TokenTree::Ident(Ident::new(position, Span::call_site())),
+                    TokenTree::Group(Group::new(Delimiter::Bracket, host.tracking)),
+                    TokenTree::Group(Group::new(Delimiter::Bracket, host.diagnostics)),
+                ]);
+                result.extend(output);
+                result
             }
-            _ => {}
         }
     }
+}
+
+fn transform(mut input: CtrsInput) -> Result<TokenStream> {
+    // The host reads the source file and passes it back to us. It reads the
+    // path as a plain string literal, so raw strings and escapes are resolved
+    // here.
+    if let Some(source) = &input.source {
+        let name = &input.name;
+        let deps = &input.deps;
+        let source = LitStr::new(&source.value(), source.span());
+        return Ok(quote! {
+            ::ctrs::ctrs! { __load_source__ #name [#deps] #source }
+        });
+    }
+
+    // Out-of-line modules are read by the host, which passes their contents
+    // back to us. The input is passed along too, so keep a copy of it.
+    let original_attrs = input.attrs.clone();
+    let original = input.items.clone();
+    let read_files = host_options(&mut input.attrs)?;
+
+    // WOO Let's do some sketchy transformations~
+    let mut walk = Transform {
+        modules: &input.modules,
+        macros: Punctuated::new(),
+        requests: Vec::new(),
+        has_exports: false,
+    };
+    walk.items(&mut input.items, "", &[], &parse_quote!(pub))?;
 
     let name = &input.name;
+    let deps = &input.deps;
+    if !walk.requests.is_empty() {
+        let requests = walk
+            .requests
+            .iter()
+            .map(|(ident, key)| quote!(#ident #key));
+        let keys = input.modules.iter().map(|(key, _)| key);
+        let contents = input.modules.iter().map(|(_, content)| content);
+        return Ok(quote! {
+            ::ctrs::ctrs! {
+                __load_modules__ [#(#requests),*] [#(#keys { #contents }),*]
+                macro crate #name(#deps); #(#original_attrs)* #(#original)*
+            }
+        });
+    }
+
+    if walk.has_exports {
+        input.items.extend(export_support(&read_files));
+        if !read_files.is_empty() {
+            input.items.push(parse_quote! {
+                #[no_mangle]
+                pub extern "C" fn __ctrs_host_read_files(
+                    _: ::proc_macro2::TokenStream,
+                ) -> ::proc_macro2::TokenStream {
+                    crate::ctrs_host::__read_files()
+                }
+            });
+        }
+    }
+
+    let macros = &walk.macros;
+    let attrs = &input.attrs;
     let items = &input.items;
+    Ok(quote! {
+        ::ctrs::ctrs! { __build_wasm__ #name [#deps] { #(#attrs)* #(#items)* } #macros }
+    })
+}
+
+/// Remove the `#![ctrs_host(..)]` attributes of a macro crate, returning
+/// the patterns of the paths it declared with `read_files("pattern", ..)`.
+///
+/// The host only passes files matching them along to the macros of the
+/// crate, and reads them from its `__ctrs_host_read_files` export.
+fn host_options(attrs: &mut Vec<Attribute>) -> Result<Vec<LitStr>> {
+    let mut read_files = Vec::new();
+    let mut result = Ok(());
+    attrs.retain(|attr| {
+        if !attr.path.is_ident("ctrs_host") {
+            return true;
+        }
+        let parsed = attr.parse_args_with(|stream: ParseStream| {
+            while !stream.is_empty() {
+                let option = stream.parse::<Ident>()?;
+                if option != "read_files" || !stream.peek(token::Paren) {
+                    return Err(Error::new(
+                        option.span(),
+                        "unknown `ctrs_host` option, expected `read_files(\"pattern\", ..)`",
+                    ));
+                }
+                let patterns;
+                parenthesized!(patterns in stream);
+                for pattern in Punctuated::<LitStr, Token![,]>::parse_terminated(&patterns)? {
+                    // The host reads the patterns as plain string literals.
+                    read_files.push(LitStr::new(&pattern.value(), pattern.span()));
+                }
+                if !stream.is_empty() {
+                    stream.parse::<Token![,]>()?;
+                }
+            }
+            Ok(())
+        });
+        if let Err(err) = parsed {
+            match &mut result {
+                Ok(()) => result = Err(err),
+                Err(errors) => errors.combine(err),
+            }
+        }
+        false
+    });
+    result.map(|()| read_files)
+}
+
+/// State of the walk over a macro crate's modules in `transform`.
+struct Transform<'a> {
+    /// Contents of out-of-line modules which have been read by the host.
+    modules: &'a [(LitStr, TokenStream)],
+    macros: Punctuated<MacroDef, Token![,]>,
+    /// Out-of-line modules to be read by the host, as the module's name and
+    /// the path of its file relative to the crate root, without `.rs`.
+    requests: Vec<(Ident, LitStr)>,
+    has_exports: bool,
+}
+
+impl Transform<'_> {
+    /// Find the macros defined in a module, which is at `path` within the
+    /// crate, is visible as `vis` and whose out-of-line modules are within
+    /// `dir`, and export them.
+    fn items(
+        &mut self,
+        items: &mut Vec<Item>,
+        dir: &str,
+        path: &[&Ident],
+        vis: &Visibility,
+    ) -> Result<()> {
+        let mut exports = Vec::new();
+        for item in items.iter_mut() {
+            match item {
+                Item::Fn(func) => {
+                    // Check for the `proc_macro`, `proc_macro_attribute`,
+                    // `proc_macro_derive` and `macro_export` attributes, and
+                    // remove them.
+                    let mut kind = None;
+                    let mut name = func.sig.ident.clone();
+                    let mut macro_export = None;
+                    for attr in &func.attrs {
+                        if attr.path.is_ident("macro_export") {
+                            macro_export = Some(attr.path.clone());
+                        } else if attr.path.is_ident("proc_macro") {
+                            kind = Some(MacroKind::Bang);
+                        } else if attr.path.is_ident("proc_macro_attribute") {
+                            kind = Some(MacroKind::Attr);
+                        } else if attr.path.is_ident("proc_macro_derive") {
+                            let (derive_name, helpers) = parse_derive_attr(attr)?;
+                            name = derive_name;
+                            kind = Some(MacroKind::Derive(helpers));
+                        }
+                    }
+                    func.attrs.retain(|attr| {
+                        !attr.path.is_ident("proc_macro")
+                            && !attr.path.is_ident("proc_macro_attribute")
+                            && !attr.path.is_ident("proc_macro_derive")
+                            && !attr.path.is_ident("macro_export")
+                    });
+
+                    match kind {
+                        Some(kind) => {
+                            // Record our macro, and generate a wasm export for
+                            // it alongside the function.
+                            let (export, item) = export(&kind, path, &func.sig.ident);
+                            self.macros.push(MacroDef {
+                                kind,
+                                export: macro_export.is_some(),
+                                vis: narrowest(vis, &func.vis),
+                                name,
+                                func: export,
+                            });
+                            exports.push(item);
+                        }
+                        None => {
+                            if let Some(attr) = macro_export {
+                                return Err(Error::new_spanned(
+                                    attr,
+                                    "`#[macro_export]` is only allowed on macro functions",
+                                ));
+                            }
+                        }
+                    }
+                }
+                Item::Mod(module) => {
+                    let key = if dir.is_empty() {
+                        module.ident.to_string()
+                    } else {
+                        format!("{}/{}", dir, module.ident)
+                    };
+
+                    if module.content.is_none() {
+                        let content = self.modules.iter().find(|(k, _)| k.value() == key);
+                        let content = match content {
+                            Some((_, content)) => content,
+                            None => {
+                                let key = LitStr::new(&key, module.ident.span());
+                                self.requests.push((module.ident.clone(), key));
+                                continue;
+                            }
+                        };
+
+                        // Splice the file's contents into the module, marking
+                        // where they came from for the host's diagnostics.
+                        let file = syn::parse2::<File>(content.clone())?;
+                        let marker = LitStr::new(&key, module.ident.span());
+                        module.attrs.insert(0, parse_quote!(#![__ctrs_file = #marker]));
+                        module.attrs.extend(file.attrs);
+                        module.content = Some((Default::default(), file.items));
+                    }
+
+                    let mut path = path.to_vec();
+                    path.push(&module.ident);
+                    let vis = narrowest(vis, &module.vis);
+                    let content = &mut module.content.as_mut().unwrap().1;
+                    self.items(content, &key, &path, &vis)?;
+                }
+                _ => {}
+            }
+        }
+
+        self.has_exports |= !exports.is_empty();
+        items.extend(exports);
+        Ok(())
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn ctrs(input: TokenStream) -> TokenStream {
+    set_panic_hook();
+
+    syn::parse2::<CtrsInput>(input)
+        .and_then(transform)
+        .unwrap_or_else(|err| err.to_compile_error())
+}
+
+struct EvalInput {
+    stmts: Vec<Stmt>,
+}
+
+impl Parse for EvalInput {
+    fn parse(stream: ParseStream) -> Result<Self> {
+        Ok(EvalInput {
+            stmts: Block::parse_within(stream)?,
+        })
+    }
+}
+
+/// Wrap the block passed to `ctrs::eval!` in a macro crate with a single
+/// export, `__ctrs_eval`, which returns the tokens of the block's value.
+#[no_mangle]
+pub extern "C" fn eval(input: TokenStream) -> TokenStream {
+    set_panic_hook();
+
+    let input = match syn::parse2::<EvalInput>(input) {
+        Ok(input) => input,
+        Err(err) => return err.to_compile_error(),
+    };
+    let stmts = &input.stmts;
     quote! {
-        ::ctrs::ctrs! { __build_wasm__ #name { #(#items)* } #macros }
+        ::ctrs::ctrs! {
+            __build_eval__ ctrs_eval {
+                #[no_mangle]
+                pub extern "C" fn __ctrs_eval(_: ::proc_macro2::TokenStream) -> ::proc_macro2::TokenStream {
+                    ::proc_macro2::set_wasm_panic_hook();
+                    ::quote::ToTokens::into_token_stream({ #(#stmts)* })
+                }
+            }
+        }
     }
 }